@@ -0,0 +1,209 @@
+use crate::files;
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+use users::Group;
+use users::User;
+
+/// The users and groups of the alternate root, if eri renders into one.
+static ACCOUNTS: Mutex<Option<Accounts>> = Mutex::new(None);
+
+/// The users and groups of a root directory.
+struct Accounts {
+    users: Vec<User>,
+    groups: Vec<Group>,
+}
+
+impl Accounts {
+    /// Read the users and groups of a root directory.
+    fn read(root: &Path) -> Result<Self> {
+        let mut users: Vec<User> = Vec::new();
+        for fields in read_entries(root, "/etc/passwd")? {
+            if fields.len() < 4 {
+                continue;
+            }
+            if let (Ok(uid), Ok(gid)) = (fields[2].parse(), fields[3].parse()) {
+                users.push(User::new(uid, &fields[0], gid));
+            }
+        }
+        let mut groups: Vec<Group> = Vec::new();
+        for fields in read_entries(root, "/etc/group")? {
+            if fields.len() < 3 {
+                continue;
+            }
+            if let Ok(gid) = fields[2].parse() {
+                groups.push(Group::new(gid, &fields[0]));
+            }
+        }
+        Ok(Accounts { users, groups })
+    }
+
+    /// Get a user by name.
+    fn user_by_name(&self, name: &str) -> Option<User> {
+        self.users.iter().find(|user| user.name() == name).cloned()
+    }
+
+    /// Get a user by uid.
+    fn user_by_uid(&self, uid: u32) -> Option<User> {
+        self.users.iter().find(|user| user.uid() == uid).cloned()
+    }
+
+    /// Get a group by name.
+    fn group_by_name(&self, name: &str) -> Option<Group> {
+        self.groups
+            .iter()
+            .find(|group| group.name() == name)
+            .cloned()
+    }
+
+    /// Get a group by gid.
+    /// A gid without a group is kept as is, since files are owned by id.
+    fn group_by_gid(&self, gid: u32) -> Group {
+        match self.groups.iter().find(|group| group.gid() == gid) {
+            Some(value) => value.clone(),
+            None => Group::new(gid, &gid.to_string()),
+        }
+    }
+
+    /// Get the owner of the files rendered from a template file owned by a uid and gid,
+    /// if the template does not set one: the root user, or the uid and gid of the template
+    /// file if there is no root user.
+    fn default_owner(&self, uid: u32, gid: u32) -> (User, Group) {
+        match self.user_by_uid(0) {
+            Some(user) => {
+                let group: Group = self.group_by_gid(user.primary_group_id());
+                (user, group)
+            }
+            None => (
+                User::new(uid, &uid.to_string(), gid),
+                self.group_by_gid(gid),
+            ),
+        }
+    }
+}
+
+/// Resolve the users and groups from the `/etc/passwd` and `/etc/group` files of an alternate
+/// root(e.g. the mount point of an image) instead of the ones of the host.
+pub fn set_root(root: &Path) -> Result<()> {
+    *ACCOUNTS.lock().unwrap() = Some(Accounts::read(root)?);
+    Ok(())
+}
+
+/// Get a user by name.
+pub fn user_by_name(name: &str) -> Option<User> {
+    match ACCOUNTS.lock().unwrap().as_ref() {
+        Some(accounts) => accounts.user_by_name(name),
+        None => users::get_user_by_name(name),
+    }
+}
+
+/// Get a user by uid.
+/// Inside an alternate root, the user must exist in the root, since its primary group is
+/// unknown otherwise.
+pub fn user_by_uid(uid: u32) -> Option<User> {
+    match ACCOUNTS.lock().unwrap().as_ref() {
+        Some(accounts) => accounts.user_by_uid(uid),
+        None => users::get_user_by_uid(uid),
+    }
+}
+
+/// Get the owner of the files rendered from a template file owned by a uid and gid, if the
+/// template does not set one and eri renders into an alternate root.
+/// The owner of the template file on the host means nothing inside the alternate root, so
+/// its root user owns the files instead.
+pub fn default_owner(uid: u32, gid: u32) -> Option<(User, Group)> {
+    ACCOUNTS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|accounts| accounts.default_owner(uid, gid))
+}
+
+/// Get a group by name.
+pub fn group_by_name(name: &str) -> Option<Group> {
+    match ACCOUNTS.lock().unwrap().as_ref() {
+        Some(accounts) => accounts.group_by_name(name),
+        None => users::get_group_by_name(name),
+    }
+}
+
+/// Get a group by gid.
+/// Inside an alternate root, a gid without a group is kept as is, since files are owned by id.
+pub fn group_by_gid(gid: u32) -> Option<Group> {
+    match ACCOUNTS.lock().unwrap().as_ref() {
+        Some(accounts) => Some(accounts.group_by_gid(gid)),
+        None => users::get_group_by_gid(gid),
+    }
+}
+
+/// Read the colon separated entries of an account file of a root directory.
+fn read_entries(root: &Path, path: &str) -> Result<Vec<Vec<String>>> {
+    let full_path: PathBuf = files::join_root(root, Path::new(path))?;
+    let contents: String = match std::fs::read_to_string(&full_path) {
+        Ok(value) => value,
+        Err(e) => return Err(anyhow!("failed to read {:?}: {}", full_path, e)),
+    };
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').map(str::to_owned).collect())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    #[test]
+    fn read_root_accounts() {
+        let root: TestDir = TestDir::new("accounts-root");
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(
+            root.join("etc/passwd"),
+            "# users\nroot:x:0:0:root:/root:/bin/sh\nredis:x:100:101::/var/lib/redis:/sbin/nologin\nbroken:x:abc:1::/:/bin/sh\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("etc/group"), "root:x:0:\nredis:x:101:\n\n").unwrap();
+
+        let accounts: Accounts = Accounts::read(&root).unwrap();
+        let redis: User = accounts.user_by_name("redis").unwrap();
+        assert_eq!((redis.uid(), redis.primary_group_id()), (100, 101));
+        assert_eq!(accounts.user_by_uid(0).unwrap().name(), "root");
+        assert!(accounts.user_by_name("broken").is_none());
+        assert!(accounts.user_by_uid(1000).is_none());
+        assert_eq!(accounts.group_by_name("redis").unwrap().gid(), 101);
+        assert_eq!(accounts.group_by_gid(1000).name(), "1000");
+    }
+
+    #[test]
+    fn default_owner_without_current_uid() {
+        let (uid, gid) = (users::get_current_uid(), users::get_current_gid());
+        let root: TestDir = TestDir::new("accounts-default-owner");
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc/group"), "root:x:0:\nredis:x:101:\n").unwrap();
+
+        // the uid of the template files is not in the root
+        let service: String = format!("redis:x:{}:101::/:/sbin/nologin\n", uid + 1);
+        std::fs::write(root.join("etc/passwd"), &service).unwrap();
+        let accounts: Accounts = Accounts::read(&root).unwrap();
+        assert!(accounts.user_by_uid(uid).is_none());
+        let (user, group) = accounts.default_owner(uid, gid);
+        assert_eq!((user.uid(), group.gid()), (uid, gid));
+
+        std::fs::write(
+            root.join("etc/passwd"),
+            format!("root:x:0:0:root:/root:/bin/sh\n{}", service),
+        )
+        .unwrap();
+        let accounts: Accounts = Accounts::read(&root).unwrap();
+        let (user, group) = accounts.default_owner(uid, gid);
+        assert_eq!(user.name(), "root");
+        assert_eq!(group.name(), "root");
+    }
+}
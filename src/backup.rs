@@ -0,0 +1,350 @@
+use crate::config::BackupConfig;
+use crate::files;
+use crate::output;
+
+use std::fs::DirBuilder;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use chrono::offset::Local;
+use chrono::DateTime;
+use chrono::Duration;
+
+use serde_json::json;
+use serde_json::Value;
+
+/// The directory where backups are kept by default, relative to the current directory.
+const DEFAULT_DIR: &str = ".eri-backups";
+
+/// The number of runs whose backups are kept by default.
+const DEFAULT_KEEP: u64 = 10;
+
+/// The name of the file describing a run, inside its backup directory.
+const RUN_FILE_NAME: &str = "run.json";
+
+/// A file as it was before a run changed it.
+#[derive(Clone, Debug)]
+pub struct BackupEntry {
+    pub path: PathBuf,
+    /// The name of the copy of the file inside the backup directory of the run, or none if
+    /// the file did not exist.
+    pub backup: Option<String>,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+}
+
+/// A run of eri, along with the backups of the files it changed.
+#[derive(Clone, Debug)]
+pub struct Run {
+    pub id: String,
+    /// The command of the run(e.g. `render`).
+    pub command: String,
+    pub time: DateTime<Local>,
+    pub entries: Vec<BackupEntry>,
+    store: PathBuf,
+    config: BackupConfig,
+}
+
+impl Run {
+    /// Start a run of a command.
+    /// Nothing is written to the backup directory until a file is backed up.
+    pub fn start(config: &BackupConfig, command: &str) -> Result<Self> {
+        let time: DateTime<Local> = Local::now();
+        Ok(Run {
+            id: time.format("%Y%m%d-%H%M%S").to_string(),
+            command: command.to_owned(),
+            time,
+            entries: Vec::new(),
+            store: store_dir(config)?,
+            config: config.clone(),
+        })
+    }
+
+    /// Back up a file before changing it, once per run.
+    /// A missing file is recorded as well, so that it's removed if the run is rolled back.
+    pub fn snapshot(&mut self, path: &Path) -> Result<()> {
+        if self.entries.iter().any(|entry| entry.path == path) {
+            return Ok(());
+        }
+        if self.entries.is_empty() {
+            self.create_dir()?;
+        }
+
+        let entry: BackupEntry = match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                let backup: String = self.entries.len().to_string();
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(self.dir().join(&backup))?
+                    .write_all(&std::fs::read(path)?)?;
+                BackupEntry {
+                    path: path.to_path_buf(),
+                    backup: Some(backup),
+                    uid: metadata.uid(),
+                    gid: metadata.gid(),
+                    mode: metadata.mode() & 0o7777,
+                }
+            }
+            Ok(_) => return Err(anyhow!("cannot back up {:?}, it's not a file", path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BackupEntry {
+                path: path.to_path_buf(),
+                backup: None,
+                uid: 0,
+                gid: 0,
+                mode: 0,
+            },
+            Err(e) => return Err(anyhow!("failed to back up {:?}: {}", path, e)),
+        };
+        log::debug!("Backed up {:?} into run {}", path, self.id);
+        self.entries.push(entry);
+        // the run is saved right away, so that it can be rolled back even if eri fails
+        self.save()
+    }
+
+    /// Finish the run, removing the backups that are no longer kept.
+    pub fn finish(&self) -> Result<()> {
+        if self.entries.is_empty() {
+            return Ok(());
+        }
+        log::info!(
+            "Backed up {} files changed by run {}",
+            self.entries.len(),
+            self.id
+        );
+
+        let keep: usize = self.config.keep.unwrap_or(DEFAULT_KEEP).max(1) as usize;
+        let runs: Vec<Run> = history(&self.config)?;
+        let excess: usize = runs.len().saturating_sub(keep);
+        for (index, run) in runs.iter().enumerate() {
+            if run.id == self.id {
+                continue;
+            }
+            let expired: bool = match self.config.keep_days {
+                Some(days) => Local::now() - run.time > Duration::days(days as i64),
+                None => false,
+            };
+            if index < excess || expired {
+                std::fs::remove_dir_all(run.dir())?;
+                log::debug!("Removed the backups of run {}", run.id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore the files changed by the run as they were before it, backing them up in
+    /// another run.
+    pub fn rollback(&self, run: &mut Run) -> Result<()> {
+        for entry in self.entries.iter().rev() {
+            run.snapshot(&entry.path)?;
+            match &entry.backup {
+                Some(backup) => {
+                    if let Some(parent) = entry.path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    files::atomic_write(
+                        &entry.path,
+                        &std::fs::read(self.dir().join(backup))?,
+                        entry.mode,
+                        Some((entry.uid, entry.gid)),
+                    )?;
+                    log::info!("Restored {:?}", entry.path);
+                }
+                None => {
+                    if entry.path.exists() {
+                        std::fs::remove_file(&entry.path)?;
+                        log::info!("Removed {:?}, it did not exist before", entry.path);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the backup directory of the run.
+    fn dir(&self) -> PathBuf {
+        self.store.join(&self.id)
+    }
+
+    /// Create the backup directory of the run, readable only by the current user, since
+    /// backed up files could hold secrets.
+    /// The directory where backups are kept is ignored by git, since it's usually inside the
+    /// directory of the eri configuration.
+    fn create_dir(&mut self) -> Result<()> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.store)?;
+        if std::fs::metadata(&self.store)?.mode() & 0o077 != 0 {
+            output::chmod(&self.store, umask::Mode::from(0o700))?;
+        }
+        let gitignore: PathBuf = self.store.join(".gitignore");
+        if !gitignore.exists() {
+            std::fs::write(gitignore, "*\n")?;
+        }
+        let id: String = self.id.clone();
+        let mut suffix: usize = 0;
+        while self.dir().exists() {
+            suffix += 1;
+            self.id = format!("{}-{}", id, suffix);
+        }
+        DirBuilder::new().mode(0o700).create(self.dir())?;
+        Ok(())
+    }
+
+    /// Save the description of the run.
+    fn save(&self) -> Result<()> {
+        let entries: Vec<Value> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                json!({
+                    "path": entry.path,
+                    "backup": entry.backup,
+                    "uid": entry.uid,
+                    "gid": entry.gid,
+                    "mode": format!("{:o}", entry.mode),
+                })
+            })
+            .collect();
+        let value: Value = json!({
+            "command": self.command,
+            "time": self.time.to_rfc3339(),
+            "files": entries,
+        });
+        std::fs::write(
+            self.dir().join(RUN_FILE_NAME),
+            serde_json::to_string_pretty(&value)?,
+        )?;
+        Ok(())
+    }
+
+    /// Load a run from its backup directory.
+    fn load(config: &BackupConfig, id: &str) -> Result<Self> {
+        let store: PathBuf = store_dir(config)?;
+        let path: PathBuf = store.join(id).join(RUN_FILE_NAME);
+        let src: String = match std::fs::read_to_string(&path) {
+            Ok(value) => value,
+            Err(e) => return Err(anyhow!("failed to read run {}: {}", id, e)),
+        };
+        let value: Value = serde_json::from_str(&src)?;
+        let invalid = || anyhow!("run {} is not valid", id);
+
+        let mut entries: Vec<BackupEntry> = Vec::new();
+        for entry in value["files"].as_array().ok_or_else(invalid)? {
+            entries.push(BackupEntry {
+                path: PathBuf::from(entry["path"].as_str().ok_or_else(invalid)?),
+                backup: entry["backup"].as_str().map(str::to_owned),
+                uid: entry["uid"].as_u64().ok_or_else(invalid)? as u32,
+                gid: entry["gid"].as_u64().ok_or_else(invalid)? as u32,
+                mode: u32::from_str_radix(entry["mode"].as_str().ok_or_else(invalid)?, 8)?,
+            });
+        }
+        let time: &str = value["time"].as_str().ok_or_else(invalid)?;
+        Ok(Run {
+            id: id.to_owned(),
+            command: value["command"].as_str().ok_or_else(invalid)?.to_owned(),
+            time: DateTime::parse_from_rfc3339(time)?.with_timezone(&Local),
+            entries,
+            store,
+            config: config.clone(),
+        })
+    }
+}
+
+/// Get the runs whose backups are kept, from the oldest to the newest.
+pub fn history(config: &BackupConfig) -> Result<Vec<Run>> {
+    let store: PathBuf = store_dir(config)?;
+    if !store.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut ids: Vec<String> = Vec::new();
+    for dir_entry in std::fs::read_dir(&store)? {
+        let dir_entry = dir_entry?;
+        if !dir_entry.path().join(RUN_FILE_NAME).is_file() {
+            continue;
+        }
+        if let Some(id) = dir_entry.file_name().to_str() {
+            ids.push(id.to_owned());
+        }
+    }
+    let mut runs: Vec<Run> = Vec::new();
+    for id in ids {
+        runs.push(Run::load(config, &id)?);
+    }
+    runs.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.id.cmp(&b.id)));
+    Ok(runs)
+}
+
+/// Get the directory where backups are kept.
+fn store_dir(config: &BackupConfig) -> Result<PathBuf> {
+    let dir: &str = config.dir.as_deref().unwrap_or(DEFAULT_DIR);
+    Ok(std::env::current_dir()?.join(dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    /// Get a backup configuration keeping the backups inside a directory.
+    fn config(dir: &Path) -> BackupConfig {
+        BackupConfig {
+            dir: Some(dir.join("backups").to_str().unwrap().to_owned()),
+            keep: None,
+            keep_days: None,
+        }
+    }
+
+    #[test]
+    fn snapshot_and_rollback() {
+        let dir: TestDir = TestDir::new("backup-rollback");
+        std::fs::create_dir_all(dir.join("backups")).unwrap();
+        output::chmod(&dir.join("backups"), umask::Mode::from(0o755)).unwrap();
+        let changed: PathBuf = dir.join("etc/redis.conf");
+        let created: PathBuf = dir.join("etc/cache.conf");
+        std::fs::create_dir_all(dir.join("etc")).unwrap();
+        std::fs::write(&changed, "port 6379\n").unwrap();
+        output::chmod(&changed, umask::Mode::from(0o640)).unwrap();
+
+        let config: BackupConfig = config(&dir);
+        let mut run: Run = Run::start(&config, "render").unwrap();
+        run.snapshot(&changed).unwrap();
+        run.snapshot(&created).unwrap();
+        run.snapshot(&changed).unwrap();
+        std::fs::write(&changed, "port 6380\n").unwrap();
+        output::chmod(&changed, umask::Mode::from(0o644)).unwrap();
+        std::fs::write(&created, "maxmemory 1g\n").unwrap();
+        run.finish().unwrap();
+
+        let store: PathBuf = dir.join("backups");
+        assert_eq!(std::fs::metadata(&store).unwrap().mode() & 0o777, 0o700);
+        assert_eq!(
+            std::fs::read_to_string(store.join(".gitignore")).unwrap(),
+            "*\n"
+        );
+
+        let runs: Vec<Run> = history(&config).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].command, "render");
+        assert_eq!(runs[0].entries.len(), 2);
+
+        let mut rollback: Run = Run::start(&config, "rollback").unwrap();
+        runs[0].rollback(&mut rollback).unwrap();
+        assert_eq!(std::fs::read_to_string(&changed).unwrap(), "port 6379\n");
+        assert_eq!(std::fs::metadata(&changed).unwrap().mode() & 0o7777, 0o640);
+        assert!(!created.exists());
+        assert!(!dir.join("etc/.redis.conf.eri-tmp").exists());
+        // the rollback itself can be rolled back
+        assert_eq!(rollback.entries.len(), 2);
+    }
+}
@@ -1,17 +1,31 @@
+use crate::accounts;
 use crate::data;
+use crate::facts;
+use crate::frontmatter::FrontMatter;
+use crate::inventory::Host;
 use crate::namespace::Namespace;
+use crate::template::Kind;
+use crate::vault;
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
 use std::path::PathBuf;
 
 use anyhow::Result;
 
+use globset::Glob;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+
 use serde_json::Map;
 use serde_json::Value;
 
 use uclicious::raw::object::ObjectError;
 use uclicious::raw::object::ObjectRef;
 use uclicious::raw::Priority;
+use uclicious::FromObject;
 use uclicious::DEFAULT_DUPLICATE_STRATEGY;
 
 use uclicious_derive::*;
@@ -24,7 +38,7 @@ use users::Group;
 use users::User;
 
 /// Map permissions mode.
-fn map_mode(src: ObjectRef) -> Result<Option<Mode>, ObjectError> {
+pub(crate) fn map_mode(src: ObjectRef) -> Result<Option<Mode>, ObjectError> {
     if src.is_null() {
         return Ok(None);
     }
@@ -48,8 +62,17 @@ fn map_mode(src: ObjectRef) -> Result<Option<Mode>, ObjectError> {
     )))
 }
 
+/// Map a ucl object to a map sorted by key.
+pub(crate) fn map_sorted<T>(src: ObjectRef) -> Result<BTreeMap<String, T>, ObjectError>
+where
+    T: FromObject<ObjectRef> + Clone,
+{
+    let map: HashMap<String, T> = FromObject::try_from(src)?;
+    Ok(map.into_iter().collect())
+}
+
 /// Map the eri config namespaces from ucl.
-fn map_namespace(src: ObjectRef) -> Result<Map<String, Value>, ObjectError> {
+pub(crate) fn map_namespace(src: ObjectRef) -> Result<Map<String, Value>, ObjectError> {
     let mut result: Map<String, Value> = Map::new();
 
     for item in src.iter() {
@@ -66,14 +89,14 @@ fn map_namespace(src: ObjectRef) -> Result<Map<String, Value>, ObjectError> {
 }
 
 /// Map an eri config user to an actual user.
-fn map_user(src: ObjectRef) -> Result<Option<User>, ObjectError> {
+pub(crate) fn map_user(src: ObjectRef) -> Result<Option<User>, ObjectError> {
     if src.is_null() {
         return Ok(None);
     }
     match src.kind() {
         ucl_type::UCL_STRING => {
             let username: String = src.as_string().unwrap();
-            if let Some(value) = users::get_user_by_name(&username) {
+            if let Some(value) = accounts::user_by_name(&username) {
                 Ok(Some(value))
             } else {
                 Err(ObjectError::Other(format!(
@@ -93,7 +116,7 @@ fn map_user(src: ObjectRef) -> Result<Option<User>, ObjectError> {
                     )))
                 }
             };
-            if let Some(value) = users::get_user_by_uid(uid) {
+            if let Some(value) = accounts::user_by_uid(uid) {
                 Ok(Some(value))
             } else {
                 Err(ObjectError::Other(format!(
@@ -109,14 +132,14 @@ fn map_user(src: ObjectRef) -> Result<Option<User>, ObjectError> {
 }
 
 /// Map an eri config user to an actual user.
-fn map_group(src: ObjectRef) -> Result<Option<Group>, ObjectError> {
+pub(crate) fn map_group(src: ObjectRef) -> Result<Option<Group>, ObjectError> {
     if src.is_null() {
         return Ok(None);
     }
     match src.kind() {
         ucl_type::UCL_STRING => {
             let groupname: String = src.as_string().unwrap();
-            if let Some(value) = users::get_group_by_name(&groupname) {
+            if let Some(value) = accounts::group_by_name(&groupname) {
                 Ok(Some(value))
             } else {
                 Err(ObjectError::Other(format!(
@@ -136,7 +159,7 @@ fn map_group(src: ObjectRef) -> Result<Option<Group>, ObjectError> {
                     )))
                 }
             };
-            if let Some(value) = users::get_group_by_gid(gid) {
+            if let Some(value) = accounts::group_by_gid(gid) {
                 Ok(Some(value))
             } else {
                 Err(ObjectError::Other(format!(
@@ -170,22 +193,23 @@ pub struct ExportConfig {
     /// By default, they are the same as the template file.
     #[ucl(default, map = "map_mode")]
     pub permissions: Option<Mode>,
+    /// The directory the export directory is inside of(e.g. the output tree of a host).
+    /// By default, it's the root directory.
+    #[ucl(default)]
+    pub root: Option<String>,
 }
 
 impl ExportConfig {
     /// Fill an export config with defaults
     fn fill_defaults(&mut self) {
         if self.dir.is_none() {
-            let current_dir_path: PathBuf;
-            match std::env::current_dir() {
-                Ok(value) => {
-                    current_dir_path = value;
-                }
+            let current_dir_path: PathBuf = match std::env::current_dir() {
+                Ok(value) => value,
                 Err(e) => {
                     log::error!("Failed to get the current directory: {:#?}", e);
                     std::process::exit(1);
                 }
-            }
+            };
 
             if let Some(value) = current_dir_path.to_str() {
                 self.dir = Some(value.to_owned());
@@ -203,12 +227,354 @@ impl Default for ExportConfig {
             user: None,
             group: None,
             permissions: None,
+            root: None,
         };
         export_config.fill_defaults();
         export_config
     }
 }
 
+/// Build a glob set out of a list of patterns.
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder: GlobSetBuilder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// The patterns selecting the kind of the files of a namespace, built once per namespace.
+#[derive(Clone, Debug)]
+pub struct KindPatterns {
+    copy: GlobSet,
+    template: GlobSet,
+}
+
+impl KindPatterns {
+    /// Get the kind of a file of the namespace as set by the `copy` and `template` patterns.
+    pub fn kind_of(&self, file_name: &str) -> Option<Kind> {
+        if self.copy.is_match(file_name) {
+            return Some(Kind::Copy);
+        }
+        if self.template.is_match(file_name) {
+            return Some(Kind::Template);
+        }
+        None
+    }
+}
+
+/// A secret read from the KV secrets engine of a Vault compatible API.
+#[derive(Clone, Debug, Uclicious)]
+pub struct SecretSource {
+    /// The path where the secrets engine is mounted.
+    /// By default, it's "secret".
+    #[ucl(default)]
+    pub mount: Option<String>,
+    /// The path of the secret, relative to the mount path.
+    pub path: String,
+    /// The version of the KV secrets engine, 1 or 2.
+    /// By default, it's 2.
+    #[ucl(default)]
+    pub kv_version: Option<i64>,
+    /// The field of the secret to use instead of the whole secret.
+    #[ucl(default)]
+    pub field: Option<String>,
+}
+
+/// A command whose output is used as data.
+#[derive(Clone, Debug, Uclicious)]
+pub struct ExecSource {
+    /// The command to run, looked up in PATH if it's not a path.
+    pub command: String,
+    /// The arguments of the command.
+    #[ucl(default)]
+    pub args: Vec<String>,
+    /// The format of the output: json, yaml, toml, ucl or text.
+    /// By default, it's json.
+    #[ucl(default)]
+    pub format: Option<String>,
+    /// The number of seconds after which the command is killed.
+    /// By default, it's 30.
+    #[ucl(default)]
+    pub timeout: Option<u64>,
+    /// The environment variables passed to the command, besides PATH, LANG and HOME.
+    #[ucl(default)]
+    pub env: Vec<String>,
+}
+
+/// The configuration of a namespace, declared in the `eri` block of its data.
+///
+/// The module directory a namespace extends(e.g. `extends = "service-base"`) is read from the
+/// block beforehand, since the data of the module is merged under the namespace data first.
+#[derive(Clone, Debug, Default, Uclicious)]
+pub struct NamespaceConfig {
+    /// Metadata for the templates of the namespace, by template file name.
+    /// Front matter declared in a template takes precedence over it.
+    #[ucl(default, map = "map_sorted")]
+    pub templates: BTreeMap<String, FrontMatter>,
+    /// Patterns matching the files that should be copied as they are.
+    #[ucl(default)]
+    pub copy: Vec<String>,
+    /// Patterns matching the files that should be rendered as templates.
+    #[ucl(default)]
+    pub template: Vec<String>,
+    /// Whether the templates inside subdirectories of the namespace directory are rendered,
+    /// into the same subdirectories of the export directory.
+    #[ucl(default)]
+    pub recursive: bool,
+    /// Data files(json, yaml, toml or ucl) merged into the namespace data, in order.
+    /// Relative paths are relative to the namespace directory.
+    #[ucl(default)]
+    pub data_files: Vec<String>,
+    /// Secrets read from vault, by the data key they are available under.
+    #[ucl(default, map = "map_sorted")]
+    pub secrets: BTreeMap<String, SecretSource>,
+    /// Commands whose output is used as data, by the data key it's available under.
+    /// The commands run in the namespace directory.
+    #[ucl(default, map = "map_sorted")]
+    pub exec: BTreeMap<String, ExecSource>,
+    /// Patterns of the data keys whose values are sensitive and never logged, besides
+    /// the ones looking like passwords, keys, tokens or secrets(e.g. `db.user`).
+    #[ucl(default)]
+    pub sensitive: Vec<String>,
+    /// The namespaces whose data is available to the templates of the namespace.
+    /// They are loaded first, and cannot import the namespace back.
+    /// By default, the data of all the namespaces is available as it's declared, its
+    /// encrypted values being decrypted, without the values loaded or interpolated by the
+    /// other namespaces.
+    #[ucl(default)]
+    pub imports: Option<Vec<String>>,
+    /// The instances of the namespace, each one rendering all the templates with its data
+    /// merged over the namespace data.
+    #[ucl(default, map = "map_namespace")]
+    pub instances: Map<String, Value>,
+    /// The export configuration of the namespace, overriding the eri export configuration.
+    /// The export directory can use the namespace data and the name of the instance(e.g.
+    /// `/etc/redis/{{instance}}`).
+    #[ucl(default)]
+    pub export: Option<ExportConfig>,
+}
+
+impl NamespaceConfig {
+    /// Get the namespaces imported by the namespace, if it declares them.
+    pub fn imports(&self) -> &[String] {
+        match &self.imports {
+            Some(value) => value,
+            None => &[],
+        }
+    }
+
+    /// Build the patterns selecting the kind of the files of the namespace.
+    pub fn kind_patterns(&self) -> Result<KindPatterns> {
+        Ok(KindPatterns {
+            copy: glob_set(&self.copy)?,
+            template: glob_set(&self.template)?,
+        })
+    }
+
+    /// Extract the configuration of a namespace from the `eri` block of its data.
+    pub fn extract(data: &mut Cow<Map<String, Value>>, name: &str) -> Result<Self> {
+        let declared: bool = match data.get(name) {
+            Some(Value::Object(namespace_data)) => namespace_data.contains_key(RESERVED_KEY),
+            _ => false,
+        };
+        if !declared {
+            return Ok(NamespaceConfig::default());
+        }
+
+        let config_data: Value = match data.to_mut().get_mut(name) {
+            Some(Value::Object(namespace_data)) => namespace_data.remove(RESERVED_KEY).unwrap(),
+            _ => unreachable!(),
+        };
+        if !config_data.is_object() {
+            return Err(anyhow!(
+                "the {} block of namespace {} should be an object",
+                RESERVED_KEY,
+                name
+            ));
+        }
+
+        let mut builder = NamespaceConfig::builder()?;
+        builder.add_chunk_full(
+            serde_json::to_string(&config_data)?,
+            Priority::default(),
+            DEFAULT_DUPLICATE_STRATEGY,
+        )?;
+        match builder.build() {
+            Ok(value) => Ok(value),
+            Err(e) => Err(anyhow!(
+                "failed to build the configuration of namespace {}: {}",
+                name,
+                e
+            )),
+        }
+    }
+}
+
+/// The configuration of the backups of the files eri changes.
+#[derive(Clone, Debug, Default, Uclicious)]
+pub struct BackupConfig {
+    /// The directory where backups are kept, outside of the namespace directories.
+    /// It's only accessible by the current user, and ignored by git.
+    /// By default, it's ".eri-backups", relative to the current directory.
+    #[ucl(default)]
+    pub dir: Option<String>,
+    /// The number of runs whose backups are kept.
+    /// By default, it's 10.
+    #[ucl(default)]
+    pub keep: Option<u64>,
+    /// The number of days backups are kept for, besides the number of runs.
+    /// By default, backups are kept regardless of their age.
+    #[ucl(default)]
+    pub keep_days: Option<u64>,
+}
+
+/// The configuration used to connect to a Vault compatible API.
+#[derive(Clone, Debug, Default, Uclicious)]
+pub struct VaultConfig {
+    /// The address of the API.
+    /// By default, it's the value of VAULT_ADDR.
+    #[ucl(default)]
+    pub address: Option<String>,
+    /// The file containing the token used to authenticate.
+    /// By default, the token is the value of VAULT_TOKEN, obtained using AppRole if a role
+    /// id is available, or read from ~/.vault-token.
+    #[ucl(default)]
+    pub token_file: Option<String>,
+    /// The AppRole role id.
+    /// By default, it's the value of VAULT_ROLE_ID.
+    #[ucl(default)]
+    pub role_id: Option<String>,
+    /// The file containing the AppRole role id.
+    #[ucl(default)]
+    pub role_id_file: Option<String>,
+    /// The file containing the AppRole secret id.
+    /// By default, it's the value of VAULT_SECRET_ID.
+    #[ucl(default)]
+    pub secret_id_file: Option<String>,
+    /// The path where the AppRole auth method is mounted.
+    /// By default, it's "approle".
+    #[ucl(default)]
+    pub approle_mount: Option<String>,
+}
+
+/// The prefix of the environment variables available as ucl variables, without it.
+const UCL_ENV_PREFIX: &str = "ERI_VAR_";
+
+/// Get the variables available in the eri configuration files.
+///
+/// - `$CONFDIR` is the directory of the eri configuration
+/// - `$NAMESPACE` is the name of the namespace, in namespace configuration files
+/// - `$HOSTNAME` is the host name
+/// - `$PROFILE` is the value of ERI_PROFILE, by default "default"
+/// - `$NAME` is the value of ERI_VAR_NAME
+///
+/// `$FILENAME` and `$CURDIR` are set from the path of each file.
+pub(crate) fn ucl_variables(namespace: Option<&str>) -> Result<Vec<(String, String)>> {
+    let mut variables: Vec<(String, String)> = Vec::new();
+    match std::env::current_dir()?.to_str() {
+        Some(value) => variables.push(("CONFDIR".to_owned(), value.to_owned())),
+        None => return Err(anyhow!("the current directory is not valid UTF-8")),
+    }
+    if let Some(value) = namespace {
+        variables.push(("NAMESPACE".to_owned(), value.to_owned()));
+    }
+    variables.push(("HOSTNAME".to_owned(), facts::hostname()?));
+    variables.push((
+        "PROFILE".to_owned(),
+        std::env::var("ERI_PROFILE").unwrap_or_else(|_| "default".to_owned()),
+    ));
+    for (key, value) in std::env::vars() {
+        if let Some(name) = key.strip_prefix(UCL_ENV_PREFIX) {
+            if !name.is_empty() {
+                variables.push((name.to_owned(), value));
+            }
+        }
+    }
+    Ok(variables)
+}
+
+/// The ucl macros including files.
+const INCLUDE_MACROS: &[&str] = &[".include", ".try_include", ".includes"];
+
+/// Make the relative paths of the files included by a ucl source relative to a directory
+/// instead of the current directory(e.g. the directory of the file it was read from).
+/// Paths starting with a variable(e.g. `$CURDIR/data.conf`) and urls are left as they are.
+///
+/// Only the source itself is changed: libucl finds the files included by an included file
+/// relative to the current directory, so they should use `$CURDIR`, the directory of the
+/// file being parsed(e.g. `.include "$CURDIR/db.conf"`).
+pub(crate) fn resolve_includes(src: &str, dir: &Path) -> String {
+    src.split_inclusive('\n')
+        .map(|line| match resolve_include(line, dir) {
+            Some(value) => Cow::Owned(value),
+            None => Cow::Borrowed(line),
+        })
+        .collect()
+}
+
+/// Make the path of a line including a file relative to a directory, if it's relative.
+fn resolve_include(line: &str, dir: &Path) -> Option<String> {
+    let trimmed: &str = line.trim_start();
+    let args: &str = INCLUDE_MACROS
+        .iter()
+        .find_map(|name| trimmed.strip_prefix(name))?;
+    // the parameters of the macro can contain quotes(e.g. `.include(prefix="db") "db.conf"`)
+    let args_start: usize = line.len() - args.len();
+    let params_end: usize = if args.trim_start().starts_with('(') {
+        args.find(')')? + 1
+    } else {
+        0
+    };
+    let path_start: usize = args_start + params_end + args[params_end..].find('"')? + 1;
+    let path_end: usize = path_start + line[path_start..].find('"')?;
+    let path: &str = &line[path_start..path_end];
+    if path.starts_with('/') || path.starts_with('$') || path.contains("://") {
+        return None;
+    }
+    Some(format!(
+        "{}{}{}",
+        &line[..path_start],
+        dir.join(path).to_str()?,
+        &line[path_end..]
+    ))
+}
+
+/// Add a namespace to an import order, after the namespaces it imports.
+/// The namespaces being visited are used to report import cycles.
+fn import_order(
+    name: &str,
+    namespaces: &BTreeMap<String, Namespace>,
+    visiting: &mut Vec<String>,
+    order: &mut Vec<String>,
+) -> Result<()> {
+    if order.iter().any(|value| value == name) {
+        return Ok(());
+    }
+    if visiting.iter().any(|value| value == name) {
+        visiting.push(name.to_owned());
+        return Err(anyhow!("namespace import cycle: {}", visiting.join(" -> ")));
+    }
+
+    visiting.push(name.to_owned());
+    for import in namespaces[name].config.imports() {
+        if !namespaces.contains_key(import) {
+            return Err(anyhow!(
+                "namespace {} imports {}, which is not a namespace",
+                name,
+                import
+            ));
+        }
+        import_order(import, namespaces, visiting, order)?;
+    }
+    visiting.pop();
+    order.push(name.to_owned());
+    Ok(())
+}
+
+/// The name of the data key reserved for values provided by eri, such as facts.
+pub const RESERVED_KEY: &str = "eri";
+
 /// The eri configuration.
 #[derive(Debug, Uclicious)]
 pub struct EriConfig {
@@ -217,6 +583,26 @@ pub struct EriConfig {
     pub export: ExportConfig,
     #[ucl(map = "map_namespace")]
     pub namespace: Map<String, Value>,
+    /// Patterns of the files inside namespace directories that are templates.
+    /// By default, all files are templates.
+    #[ucl(default)]
+    pub include: Vec<String>,
+    /// Patterns of the files inside namespace directories that are not templates.
+    #[ucl(default)]
+    pub exclude: Vec<String>,
+    /// Whether facts about the local host should be available to templates, under `eri.facts`.
+    #[ucl(default)]
+    pub facts: bool,
+    /// The configuration used to read secrets from vault.
+    #[ucl(default)]
+    pub vault: VaultConfig,
+    /// Directories, besides the namespace directories, whose files templates can read.
+    /// Relative paths are relative to the current directory.
+    #[ucl(default)]
+    pub allow_dirs: Vec<String>,
+    /// The configuration of the backups of the files eri changes.
+    #[ucl(default)]
+    pub backup: BackupConfig,
 }
 
 impl EriConfig {
@@ -228,11 +614,16 @@ impl EriConfig {
         }
 
         let eri_config_string: String = std::fs::read_to_string("eri.conf")?;
+        let current_dir_path: PathBuf = std::env::current_dir()?;
 
         let mut eri_config_builder = EriConfig::builder()?;
+        for (name, value) in ucl_variables(None)? {
+            eri_config_builder.register_variable(name, value);
+        }
+        eri_config_builder.set_filevars(current_dir_path.join("eri.conf"), true)?;
         eri_config_builder
             .add_chunk_full(
-                eri_config_string,
+                resolve_includes(&eri_config_string, &current_dir_path),
                 Priority::default(),
                 DEFAULT_DUPLICATE_STRATEGY,
             )
@@ -240,6 +631,12 @@ impl EriConfig {
 
         match eri_config_builder.build() {
             Ok(mut value) => {
+                if value.namespace.contains_key(RESERVED_KEY) {
+                    return Err(anyhow!(
+                        "{} is reserved and cannot be used as a namespace name",
+                        RESERVED_KEY
+                    ));
+                }
                 value.export.fill_defaults();
                 Ok(value)
             }
@@ -247,16 +644,137 @@ impl EriConfig {
         }
     }
 
-    /// Get the namespaces of the configuration.
-    pub fn namespaces(&self) -> Result<Vec<Namespace>> {
-        let mut namespaces: Vec<Namespace> = Vec::new();
+    /// Make facts available to all templates.
+    pub fn set_facts(&mut self, facts: Value) {
+        let reserved: &mut Value = self
+            .namespace
+            .entry(RESERVED_KEY)
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Value::Object(reserved) = reserved {
+            reserved.insert("facts".to_owned(), facts);
+        }
+    }
+
+    /// Load the namespaces of the configuration, each one after the namespaces it imports.
+    /// Their data sources(data files, secrets and commands) are resolved once, and shared
+    /// by the instances of the namespaces for every host.
+    pub fn namespaces(&self) -> Result<Vec<Namespace<'_>>> {
+        let mut namespaces: BTreeMap<String, Namespace> = BTreeMap::new();
+        let mut vault: Option<vault::Client> = None;
         for (name, _) in &self.namespace {
-            namespaces.push(Namespace::new(
-                name,
-                &self.export,
-                Cow::Borrowed(&self.namespace),
-            )?);
+            if name == RESERVED_KEY {
+                continue;
+            }
+            namespaces.insert(
+                name.clone(),
+                Namespace::new(name, self, Cow::Borrowed(&self.namespace), &mut vault)?,
+            );
+        }
+
+        let mut order: Vec<String> = Vec::new();
+        for name in namespaces.keys() {
+            import_order(name, &namespaces, &mut Vec::new(), &mut order)?;
+        }
+
+        Ok(order
+            .iter()
+            .map(|name| namespaces.remove(name).unwrap())
+            .collect())
+    }
+}
+
+/// Get the instances of the loaded namespaces, for a host of an inventory if there is one.
+/// References between values are resolved once the imported data is available.
+/// The data of a host is layered over the data of each namespace.
+pub fn instances<'a>(
+    namespaces: &[Namespace<'a>],
+    host: Option<&Host>,
+) -> Result<Vec<Namespace<'a>>> {
+    let mut resolved: Vec<Namespace> = Vec::new();
+    let mut instances: Vec<Namespace> = Vec::new();
+    for namespace in namespaces {
+        let mut namespace: Namespace = namespace.clone();
+        if let Some(host) = host {
+            namespace.set_host(host)?;
+        }
+        for import in &namespace.config.imports().to_vec() {
+            let imported: &Namespace = resolved.iter().find(|ns| &ns.name == import).unwrap();
+            namespace.import(imported);
         }
-        Ok(namespaces)
+        for mut instance in namespace.instances()? {
+            instance.interpolate()?;
+            instance.set_reserved_data();
+            instance.render_export_dir()?;
+            instances.push(instance);
+        }
+        // other namespaces import the data of the namespace without any instance data
+        namespace.interpolate()?;
+        resolved.push(namespace);
+    }
+    Ok(instances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    /// Get the data of the namespaces from a json value.
+    fn namespaces(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("namespaces should be an object"),
+        }
+    }
+
+    #[test]
+    fn resolve_relative_includes() {
+        let src: &str = "a = 1;\n.include \"db.conf\"\n  .try_include(priority=2,prefix=\"x\") \"sub/x.conf\"\n.include \"/etc/x.conf\"\n.includes \"$CURDIR/y.conf\"\n";
+        assert_eq!(
+            resolve_includes(src, Path::new("/ns")),
+            "a = 1;\n.include \"/ns/db.conf\"\n  .try_include(priority=2,prefix=\"x\") \"/ns/sub/x.conf\"\n.include \"/etc/x.conf\"\n.includes \"$CURDIR/y.conf\"\n"
+        );
+    }
+
+    #[test]
+    fn extract_eri_block() {
+        let data: Map<String, Value> = namespaces(json!({
+            "web": {
+                "imports": ["db"],
+                "eri": {
+                    "imports": ["db"],
+                    "extends": "service-base",
+                    "copy": ["*.png"],
+                    "template": ["*.png.tmpl"],
+                },
+            },
+        }));
+        let mut data: Cow<Map<String, Value>> = Cow::Owned(data);
+        let config: NamespaceConfig = NamespaceConfig::extract(&mut data, "web").unwrap();
+        assert_eq!(config.imports(), ["db"]);
+        // keys outside of the eri block stay data
+        assert_eq!(data["web"], json!({"imports": ["db"]}));
+
+        let kind_patterns: KindPatterns = config.kind_patterns().unwrap();
+        assert_eq!(kind_patterns.kind_of("logo.png"), Some(Kind::Copy));
+        assert_eq!(kind_patterns.kind_of("logo.png.tmpl"), Some(Kind::Template));
+        assert_eq!(kind_patterns.kind_of("app.conf"), None);
+    }
+
+    #[test]
+    fn extract_without_eri_block() {
+        let data: Map<String, Value> = namespaces(json!({"web": {"copy": "data"}}));
+        let mut data: Cow<Map<String, Value>> = Cow::Borrowed(&data);
+        let config: NamespaceConfig = NamespaceConfig::extract(&mut data, "web").unwrap();
+        assert!(config.copy.is_empty());
+        assert!(matches!(data, Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn extract_invalid_eri_block() {
+        let data: Map<String, Value> = namespaces(json!({"web": {"eri": "value"}}));
+        let mut data: Cow<Map<String, Value>> = Cow::Owned(data);
+        assert!(NamespaceConfig::extract(&mut data, "web").is_err());
     }
 }
@@ -0,0 +1,368 @@
+use crate::data;
+use crate::sensitive;
+
+use std::fs::DirBuilder;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+use age::x25519::Identity;
+use age::x25519::Recipient;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use serde_json::Value;
+
+/// The prefix of an encrypted value.
+const PREFIX: &str = "ENC[age,";
+
+/// The suffix of an encrypted value.
+const SUFFIX: &str = "]";
+
+/// The extension of encrypted files.
+pub const EXTENSION: &str = "age";
+
+/// The signals that would stop eri while a file is edited, leaving the decrypted file behind.
+const EDIT_SIGNALS: &[libc::c_int] = &[libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM];
+
+/// The identities loaded so far.
+static IDENTITIES: Mutex<Option<Vec<Identity>>> = Mutex::new(None);
+
+/// Check whether a string is an encrypted value.
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX) && value.ends_with(SUFFIX)
+}
+
+/// Encrypt a string into an encrypted value.
+pub fn encrypt_value(value: &str, recipients: &[String]) -> Result<String> {
+    let ciphertext: Vec<u8> = encrypt(value.as_bytes(), recipients)?;
+    Ok(format!(
+        "{}{}{}",
+        PREFIX,
+        base64::encode(ciphertext),
+        SUFFIX
+    ))
+}
+
+/// Decrypt an encrypted value into a string.
+pub fn decrypt_value(value: &str) -> Result<String> {
+    if !is_encrypted(value) {
+        return Err(anyhow!("value is not of the form {}...{}", PREFIX, SUFFIX));
+    }
+    let ciphertext: Vec<u8> = base64::decode(&value[PREFIX.len()..value.len() - SUFFIX.len()])?;
+    let plaintext: String = String::from_utf8(decrypt(&ciphertext)?)?;
+    sensitive::mark_str(&plaintext);
+    Ok(plaintext)
+}
+
+/// Check whether a value contains encrypted values.
+pub fn contains_encrypted(value: &Value) -> bool {
+    match value {
+        Value::String(string) => is_encrypted(string),
+        Value::Array(array) => array.iter().any(contains_encrypted),
+        Value::Object(map) => map.values().any(contains_encrypted),
+        _ => false,
+    }
+}
+
+/// Decrypt all the encrypted values inside a value.
+/// The decrypted values are never interpolated.
+pub fn decrypt_all(value: &mut Value) -> Result<()> {
+    match value {
+        Value::String(string) if is_encrypted(string) => {
+            *string = decrypt_value(string)?;
+            data::mark_verbatim_str(string);
+        }
+        Value::Array(array) => {
+            for item in array {
+                decrypt_all(item)?;
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                if let Err(e) = decrypt_all(item) {
+                    return Err(anyhow!("failed to decrypt {}: {}", key, e));
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Encrypt data to a list of recipients.
+/// Without recipients, the data is encrypted to the local identities.
+pub fn encrypt(plaintext: &[u8], recipients: &[String]) -> Result<Vec<u8>> {
+    let mut age_recipients: Vec<Recipient> = Vec::new();
+    for recipient in recipients {
+        match Recipient::from_str(recipient) {
+            Ok(value) => age_recipients.push(value),
+            Err(e) => return Err(anyhow!("invalid recipient {}: {}", recipient, e)),
+        }
+    }
+    if age_recipients.is_empty() {
+        for identity in identities()? {
+            age_recipients.push(identity.to_public());
+        }
+    }
+
+    let encryptor: age::Encryptor = age::Encryptor::with_recipients(
+        age_recipients
+            .iter()
+            .map(|recipient| recipient as &dyn age::Recipient),
+    )?;
+    let mut ciphertext: Vec<u8> = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut ciphertext)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?;
+    Ok(ciphertext)
+}
+
+/// Decrypt data using the local identities.
+pub fn decrypt(ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let identities: Vec<Identity> = identities()?;
+    let decryptor = age::Decryptor::new(ciphertext)?;
+    if decryptor.is_scrypt() {
+        return Err(anyhow!("passphrase encrypted data is not supported"));
+    }
+    let mut reader = decryptor.decrypt(
+        identities
+            .iter()
+            .map(|identity| identity as &dyn age::Identity),
+    )?;
+
+    let mut plaintext: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+/// Decrypt an encrypted file.
+pub fn decrypt_file(path: &Path) -> Result<Vec<u8>> {
+    match decrypt(&std::fs::read(path)?) {
+        Ok(value) => Ok(value),
+        Err(e) => Err(anyhow!("failed to decrypt {:?}: {}", path, e)),
+    }
+}
+
+/// Edit an encrypted file in a text editor, creating it if it does not exist.
+///
+/// The file is decrypted into a private temporary directory, opened in VISUAL or EDITOR,
+/// then encrypted back to the recipients. The decrypted file is removed however editing
+/// ends, including when eri is interrupted while the editor runs.
+pub fn edit_file(path: &Path, recipients: &[String]) -> Result<()> {
+    let plaintext: Vec<u8> = if path.exists() {
+        decrypt_file(path)?
+    } else {
+        Vec::new()
+    };
+
+    let edit_file: EditFile = EditFile::create(path, &plaintext)?;
+    let _signals: SignalGuard = SignalGuard::set();
+    edit_and_encrypt(&edit_file.path, path, &plaintext, recipients)
+}
+
+/// A decrypted file being edited, inside a directory only the current user can access.
+/// The directory is removed when it's dropped.
+struct EditFile {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl EditFile {
+    /// Create the decrypted file for an encrypted file, in the temporary directory.
+    fn create(path: &Path, plaintext: &[u8]) -> Result<Self> {
+        // the name of the file is kept for the editor to recognize its type
+        let file_name: &str = match path.file_name().and_then(|name| name.to_str()) {
+            Some(value) => value
+                .strip_suffix(&format!(".{}", EXTENSION))
+                .unwrap_or(value),
+            None => return Err(anyhow!("invalid file name: {:?}", path)),
+        };
+        let dir: PathBuf = std::env::temp_dir().join(format!("eri-edit-{:016x}", OsRng.next_u64()));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let edit_file: EditFile = EditFile {
+            path: dir.join(file_name),
+            dir,
+        };
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&edit_file.path)?
+            .write_all(plaintext)?;
+        Ok(edit_file)
+    }
+}
+
+impl Drop for EditFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            log::error!("Failed to remove the decrypted file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Keeps the signals that would stop eri from doing so until it's dropped.
+/// The editor still receives them, as handlers are reset when it's executed.
+struct SignalGuard {
+    previous: Vec<(libc::c_int, libc::sighandler_t)>,
+}
+
+impl SignalGuard {
+    /// Handle the signals by doing nothing.
+    fn set() -> Self {
+        let handler: extern "C" fn(libc::c_int) = ignore_signal;
+        let previous: Vec<(libc::c_int, libc::sighandler_t)> = EDIT_SIGNALS
+            .iter()
+            .map(|signal| {
+                (*signal, unsafe {
+                    libc::signal(*signal, handler as libc::sighandler_t)
+                })
+            })
+            .collect();
+        SignalGuard { previous }
+    }
+}
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        for (signal, handler) in &self.previous {
+            unsafe { libc::signal(*signal, *handler) };
+        }
+    }
+}
+
+/// A signal handler that does nothing.
+extern "C" fn ignore_signal(_: libc::c_int) {}
+
+/// Open a file in a text editor, then encrypt it into another file if it was changed.
+fn edit_and_encrypt(
+    edit_path: &Path,
+    path: &Path,
+    plaintext: &[u8],
+    recipients: &[String],
+) -> Result<()> {
+    let editor: String = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(edit_path)
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("{} failed with {}", editor, status));
+    }
+
+    let edited: Vec<u8> = std::fs::read(edit_path)?;
+    if edited == plaintext && path.exists() {
+        log::info!("{:?} was not changed", path);
+        return Ok(());
+    }
+    std::fs::write(path, encrypt(&edited, recipients)?)?;
+    log::info!("{:?} was encrypted", path);
+    Ok(())
+}
+
+/// Get the local identities.
+///
+/// They are read from ERI_AGE_KEY if it's set, otherwise from the file at ERI_AGE_KEY_FILE,
+/// which defaults to ~/.config/eri/age.key.
+fn identities() -> Result<Vec<Identity>> {
+    let mut identities = IDENTITIES.lock().unwrap();
+    if let Some(value) = identities.as_ref() {
+        return Ok(value.clone());
+    }
+
+    let keys: String = match std::env::var("ERI_AGE_KEY") {
+        Ok(value) => value,
+        Err(_) => {
+            let key_file: PathBuf = identity_file()?;
+            match std::fs::read_to_string(&key_file) {
+                Ok(value) => value,
+                Err(e) => {
+                    return Err(anyhow!(
+                        "failed to read identity file {:?}: {}",
+                        key_file,
+                        e
+                    ))
+                }
+            }
+        }
+    };
+    let mut value: Vec<Identity> = Vec::new();
+    for line in keys.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match Identity::from_str(line) {
+            Ok(identity) => value.push(identity),
+            Err(e) => return Err(anyhow!("invalid age identity: {}", e)),
+        }
+    }
+    if value.is_empty() {
+        return Err(anyhow!("no age identity found"));
+    }
+
+    *identities = Some(value.clone());
+    Ok(value)
+}
+
+/// Get the path of the identity file.
+fn identity_file() -> Result<PathBuf> {
+    if let Some(value) = std::env::var_os("ERI_AGE_KEY_FILE") {
+        return Ok(PathBuf::from(value));
+    }
+    let config_dir: PathBuf = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(value) => PathBuf::from(value),
+        None => match std::env::var_os("HOME") {
+            Some(value) => PathBuf::from(value).join(".config"),
+            None => return Err(anyhow!("cannot find the identity file, HOME is not set")),
+        },
+    };
+    Ok(config_dir.join("eri").join("age.key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+    use crate::testing::TestDir;
+
+    use serde_json::json;
+
+    #[test]
+    fn encrypt_decrypt_edit() {
+        testing::age_identity();
+
+        let encrypted: String = encrypt_value("hunter22", &[]).unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(decrypt_value(&encrypted).unwrap(), "hunter22");
+        assert!(decrypt_value("hunter22").is_err());
+
+        let mut value: Value = json!({"db": {"password": encrypted, "port": 5432}});
+        assert!(contains_encrypted(&value));
+        decrypt_all(&mut value).unwrap();
+        assert_eq!(value, json!({"db": {"password": "hunter22", "port": 5432}}));
+        assert!(!contains_encrypted(&value));
+
+        let dir: TestDir = TestDir::new("crypt-edit");
+        let path: PathBuf = dir.join("secret.yml.age");
+        std::env::set_var("VISUAL", "f() { echo \"$1\" > \"$1\"; }; f");
+        edit_file(&path, &[]).unwrap();
+        let edit_path: String = String::from_utf8(decrypt_file(&path).unwrap()).unwrap();
+        assert!(edit_path.trim().ends_with("/secret.yml"), "{}", edit_path);
+        assert!(!Path::new(edit_path.trim()).parent().unwrap().exists());
+    }
+}
@@ -1,8 +1,18 @@
+use crate::accounts;
+use crate::crypt;
+use crate::sensitive;
+
+use std::collections::BTreeSet;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Mutex;
 
 use anyhow::Result;
 
 use uclicious::raw::object::ObjectRef;
+use uclicious::Parser;
+use uclicious::Priority;
+use uclicious::DEFAULT_DUPLICATE_STRATEGY;
 
 use uclicious_libucl_sys::ucl_type;
 
@@ -15,6 +25,25 @@ use serde_json::Value;
 use users::Group;
 use users::User;
 
+/// The strings loaded from secrets, commands and encrypted values, which are never interpolated.
+static VERBATIM: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Keep all the strings inside a value from being interpolated, since they are not declared
+/// in the configuration(e.g. secrets, which can contain `${`).
+pub fn mark_verbatim(value: &Value) {
+    match value {
+        Value::String(value) => mark_verbatim_str(value),
+        Value::Array(array) => array.iter().for_each(mark_verbatim),
+        Value::Object(map) => map.values().for_each(mark_verbatim),
+        _ => {}
+    }
+}
+
+/// Keep a string from being interpolated.
+pub fn mark_verbatim_str(value: &str) {
+    VERBATIM.lock().unwrap().insert(value.to_owned());
+}
+
 /// Convert an ObjectRef to a Value.
 pub fn object_ref_to_value(src: ObjectRef) -> Result<Value> {
     match src.kind() {
@@ -53,11 +82,252 @@ pub fn object_ref_to_value(src: ObjectRef) -> Result<Value> {
     }
 }
 
+/// Merge a value into another one.
+/// Objects are merged key by key, while any other value replaces the existing one.
+pub fn merge(dst: &mut Value, src: Value) {
+    match (dst, src) {
+        (Value::Object(dst_map), Value::Object(src_map)) => {
+            for (key, value) in src_map {
+                match dst_map.get_mut(&key) {
+                    Some(dst_value) => merge(dst_value, value),
+                    None => {
+                        dst_map.insert(key, value);
+                    }
+                }
+            }
+        }
+        (dst, src) => *dst = src,
+    }
+}
+
+/// Parse data in one of the supported formats: json, yaml, toml or ucl.
+pub fn parse(src: &str, format: &str) -> Result<Value> {
+    match format {
+        "json" => Ok(serde_json::from_str(src)?),
+        "yaml" | "yml" => Ok(serde_yaml::from_str(src)?),
+        "toml" => Ok(toml::from_str(src)?),
+        "ucl" | "conf" => parse_ucl(src),
+        _ => Err(anyhow!("unsupported data format: {}", format)),
+    }
+}
+
+/// Parse ucl data.
+pub fn parse_ucl(src: &str) -> Result<Value> {
+    let mut parser: Parser = Parser::default();
+    parser.add_chunk_full(src, Priority::default(), DEFAULT_DUPLICATE_STRATEGY)?;
+    let mut map: Map<String, Value> = Map::new();
+    for item in parser.get_object()?.iter() {
+        let item_key = item.key().unwrap();
+        map.insert(item_key, object_ref_to_value(item)?);
+    }
+    Ok(Value::Object(map))
+}
+
+/// Load a data file, guessing its format from its extension.
+/// Encrypted files have their format guessed from the extension preceding `.age`.
+pub fn load_file(path: &Path) -> Result<Value> {
+    let encrypted: bool = path.extension().and_then(|ext| ext.to_str()) == Some(crypt::EXTENSION);
+    let format_path: &Path = if encrypted {
+        Path::new(path.file_stem().unwrap())
+    } else {
+        path
+    };
+    let format: &str = match format_path.extension().and_then(|ext| ext.to_str()) {
+        Some(value) => value,
+        None => return Err(anyhow!("cannot guess the format of data file {:?}", path)),
+    };
+
+    let src: String = if encrypted {
+        String::from_utf8(crypt::decrypt_file(path)?)?
+    } else {
+        match std::fs::read_to_string(path) {
+            Ok(value) => value,
+            Err(e) => return Err(anyhow!("failed to read data file {:?}: {}", path, e)),
+        }
+    };
+    match parse(&src, format) {
+        Ok(value) => {
+            if encrypted {
+                sensitive::mark(&value);
+            }
+            Ok(value)
+        }
+        Err(e) => Err(anyhow!("failed to parse data file {:?}: {}", path, e)),
+    }
+}
+
+/// Get the paths matching a pattern, relative to a base directory, in alphabetical order.
+pub fn glob_paths(base_path: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let full_pattern: PathBuf = base_path.join(pattern);
+    let full_pattern_str: &str = match full_pattern.to_str() {
+        Some(value) => value,
+        None => return Err(anyhow!("invalid path pattern: {:?}", full_pattern)),
+    };
+
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for path in glob::glob(full_pattern_str)? {
+        paths.push(path?);
+    }
+    if paths.is_empty() && !pattern.contains(['*', '?', '[']) {
+        return Err(anyhow!("{:?} not found", full_pattern));
+    }
+    Ok(paths)
+}
+
+/// Look up a dot separated key(e.g. `vault.address`) in a data map.
+pub fn lookup<'a>(data: &'a Map<String, Value>, key: &str) -> Option<&'a Value> {
+    let mut parts = key.split('.');
+    let mut value: &Value = data.get(parts.next()?)?;
+    for part in parts {
+        value = match value {
+            Value::Object(map) => map.get(part)?,
+            Value::Array(array) => array.get(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Resolve the references to other values(e.g. `${vault.data_dir}`) inside the strings of a
+/// key of the data.
+///
+/// A string made of a single reference is replaced by the referenced value, while references
+/// inside longer strings must be strings, numbers or booleans. `$${` is a literal `${`.
+/// Strings marked as verbatim are left as they are.
+pub fn interpolate(data: &mut Map<String, Value>, key: &str) -> Result<()> {
+    if !data.contains_key(key) {
+        return Ok(());
+    }
+    let value: Value = resolve(key, data, &mut Vec::new())?;
+    data.insert(key.to_owned(), value);
+    Ok(())
+}
+
+/// Get a value of the data with its references resolved.
+fn resolve<'a>(
+    key: &str,
+    data: &'a Map<String, Value>,
+    resolving: &mut Vec<(&'a Value, String)>,
+) -> Result<Value> {
+    match lookup(data, key) {
+        Some(value) => resolve_value(value, key.to_owned(), data, resolving),
+        None => match resolving.last() {
+            Some((_, from)) => Err(anyhow!("unresolved reference ${{{}}} in {}", key, from)),
+            None => Ok(Value::Null),
+        },
+    }
+}
+
+/// Resolve the references inside a value of the data, found at a path.
+/// The values being resolved, along with their paths, are used to report reference cycles.
+fn resolve_value<'a>(
+    value: &'a Value,
+    path: String,
+    data: &'a Map<String, Value>,
+    resolving: &mut Vec<(&'a Value, String)>,
+) -> Result<Value> {
+    if let Some(index) = resolving
+        .iter()
+        .position(|(item, _)| std::ptr::eq(*item, value))
+    {
+        let mut cycle: Vec<&str> = resolving[index..]
+            .iter()
+            .map(|(_, item_path)| item_path.as_str())
+            .collect();
+        cycle.push(&path);
+        return Err(anyhow!("reference cycle: {}", cycle.join(" -> ")));
+    }
+
+    resolving.push((value, path.clone()));
+    let result: Value = match value {
+        Value::String(string) => resolve_string(string, data, resolving)?,
+        Value::Array(array) => {
+            let mut result: Vec<Value> = Vec::new();
+            for (index, item) in array.iter().enumerate() {
+                let item_path: String = format!("{}.{}", path, index);
+                result.push(resolve_value(item, item_path, data, resolving)?);
+            }
+            Value::Array(result)
+        }
+        Value::Object(map) => {
+            let mut result: Map<String, Value> = Map::new();
+            for (item_key, item) in map {
+                let item_path: String = format!("{}.{}", path, item_key);
+                result.insert(
+                    item_key.clone(),
+                    resolve_value(item, item_path, data, resolving)?,
+                );
+            }
+            Value::Object(result)
+        }
+        value => value.clone(),
+    };
+    resolving.pop();
+    Ok(result)
+}
+
+/// Resolve the references inside a string of the data.
+fn resolve_string<'a>(
+    string: &str,
+    data: &'a Map<String, Value>,
+    resolving: &mut Vec<(&'a Value, String)>,
+) -> Result<Value> {
+    if VERBATIM.lock().unwrap().contains(string) {
+        return Ok(Value::String(string.to_owned()));
+    }
+    let key: String = resolving.last().unwrap().1.clone();
+    let trimmed: &str = string.trim();
+    if trimmed.starts_with("${")
+        && trimmed.ends_with('}')
+        && trimmed.matches("${").count() == 1
+        && trimmed.matches('}').count() == 1
+    {
+        return resolve(trimmed[2..trimmed.len() - 1].trim(), data, resolving);
+    }
+
+    let mut result: String = String::new();
+    let mut rest: &str = string;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        let end: usize = match rest[start..].find('}') {
+            Some(value) => start + value,
+            None => return Err(anyhow!("unterminated reference in {}", key)),
+        };
+        let reference: &str = rest[start + 2..end].trim();
+        match resolve(reference, data, resolving)? {
+            Value::String(value) => result.push_str(&value),
+            Value::Number(value) => result.push_str(&value.to_string()),
+            Value::Bool(value) => result.push_str(&value.to_string()),
+            Value::Null => {}
+            _ => {
+                return Err(anyhow!(
+                    "{} references {} inside a string, but it's a list or an object",
+                    key,
+                    reference
+                ))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(Value::String(result))
+}
+
 #[cfg(target_os = "linux")]
 pub fn get_user(path: &PathBuf) -> Result<User> {
     use std::os::linux::fs::MetadataExt;
-    let uid = std::fs::File::open(path)?.metadata()?.st_uid();
-    match users::get_user_by_uid(uid) {
+    let metadata = std::fs::File::open(path)?.metadata()?;
+    let (uid, gid) = (metadata.st_uid(), metadata.st_gid());
+    if let Some((user, _)) = accounts::default_owner(uid, gid) {
+        return Ok(user);
+    }
+    match accounts::user_by_uid(uid) {
         Some(value) => Ok(value),
         None => Err(anyhow!("no user found with the uid {}", uid)),
     }
@@ -66,8 +336,12 @@ pub fn get_user(path: &PathBuf) -> Result<User> {
 #[cfg(target_os = "linux")]
 pub fn get_group(path: &PathBuf) -> Result<Group> {
     use std::os::linux::fs::MetadataExt;
-    let gid = std::fs::File::open(path)?.metadata()?.st_gid();
-    match users::get_group_by_gid(gid) {
+    let metadata = std::fs::File::open(path)?.metadata()?;
+    let (uid, gid) = (metadata.st_uid(), metadata.st_gid());
+    if let Some((_, group)) = accounts::default_owner(uid, gid) {
+        return Ok(group);
+    }
+    match accounts::group_by_gid(gid) {
         Some(value) => Ok(value),
         None => Err(anyhow!("no group found with the gid {}", gid)),
     }
@@ -76,7 +350,134 @@ pub fn get_group(path: &PathBuf) -> Result<Group> {
 #[cfg(target_os = "linux")]
 pub fn get_permissions(path: &PathBuf) -> Result<Mode> {
     use std::os::linux::fs::MetadataExt;
-    Ok(Mode::from(
-        std::fs::File::open(&path)?.metadata()?.st_mode(),
-    ))
+    Ok(Mode::from(std::fs::File::open(path)?.metadata()?.st_mode()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    use serde_json::json;
+
+    #[test]
+    fn parse_formats() {
+        let expected: Value = json!({"db": {"port": 5432, "hosts": ["a", "b"]}});
+        let sources: &[(&str, &str)] = &[
+            ("json", r#"{"db": {"port": 5432, "hosts": ["a", "b"]}}"#),
+            ("yaml", "db:\n  port: 5432\n  hosts: [a, b]\n"),
+            ("toml", "[db]\nport = 5432\nhosts = [\"a\", \"b\"]\n"),
+            ("ucl", "db { port = 5432; hosts = [a, b]; }"),
+        ];
+        for (format, src) in sources {
+            assert_eq!(parse(src, format).unwrap(), expected, "{}", format);
+        }
+        assert!(parse("", "ini").is_err());
+    }
+
+    /// Interpolate the data of a namespace.
+    fn interpolated(value: Value) -> Result<Value> {
+        let mut data: Map<String, Value> = match value {
+            Value::Object(map) => map,
+            _ => panic!("data should be an object"),
+        };
+        interpolate(&mut data, "ns")?;
+        Ok(data.remove("ns").unwrap())
+    }
+
+    #[test]
+    fn interpolate_whole_and_embedded() {
+        let value: Value = interpolated(json!({
+            "ns": {
+                "dir": "/var/lib",
+                "data_dir": "${ns.dir}/db",
+                "ports": [80, 443],
+                "copy": " ${ns.ports} ",
+                "url": "http://${other.host}:${ns.ports.1}",
+                "enabled": "${other.enabled}",
+            },
+            "other": {"host": "db", "enabled": true},
+        }))
+        .unwrap();
+        assert_eq!(value["data_dir"], "/var/lib/db");
+        assert_eq!(value["copy"], json!([80, 443]));
+        assert_eq!(value["url"], "http://db:443");
+        assert_eq!(value["enabled"], true);
+    }
+
+    #[test]
+    fn interpolate_dotted_keys() {
+        let value: Value = interpolated(json!({
+            "ns": {"hosts": {"db.example.com": {"port": 5432}}, "host": "${ns.name}", "name": "x"},
+        }))
+        .unwrap();
+        assert_eq!(value["hosts"]["db.example.com"]["port"], 5432);
+        assert_eq!(value["host"], "x");
+    }
+
+    #[test]
+    fn interpolate_escape() {
+        let value: Value = interpolated(json!({
+            "ns": {"literal": "$${ns.name}", "mixed": "${ns.name}-$${HOME}", "name": "x"},
+        }))
+        .unwrap();
+        assert_eq!(value["literal"], "${ns.name}");
+        assert_eq!(value["mixed"], "x-${HOME}");
+    }
+
+    #[test]
+    fn interpolate_skips_verbatim() {
+        mark_verbatim(&json!({"password": "pw-${2f7b", "token": "${ns.name}-2f7b"}));
+        let value: Value = interpolated(json!({
+            "ns": {
+                "password": "pw-${2f7b",
+                "token": "${ns.name}-2f7b",
+                "url": "redis://:${ns.password}@db",
+                "name": "x",
+            },
+        }))
+        .unwrap();
+        assert_eq!(value["password"], "pw-${2f7b");
+        assert_eq!(value["token"], "${ns.name}-2f7b");
+        assert_eq!(value["url"], "redis://:pw-${2f7b@db");
+    }
+
+    #[test]
+    fn interpolate_errors() {
+        let cycle: String = interpolated(json!({"ns": {"a": "${ns.b}", "b": "x${ns.a}"}}))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            cycle.contains("reference cycle: ns.a -> ns.b -> ns.a"),
+            "{}",
+            cycle
+        );
+        assert!(interpolated(json!({"ns": {"a": {"b": "${ns.a}"}}})).is_err());
+        assert!(interpolated(json!({"ns": {"a": "${ns.missing}"}})).is_err());
+        let unterminated: String = interpolated(json!({"ns": {"a": "pw-6a0e ${ns.b", "b": 1}}))
+            .unwrap_err()
+            .to_string();
+        assert!(!unterminated.contains("pw-6a0e"), "{}", unterminated);
+        assert!(interpolated(json!({"ns": {"a": "x${ns.b}", "b": [1]}})).is_err());
+    }
+
+    #[test]
+    fn merge_objects() {
+        let mut dst: Value = json!({"db": {"port": 5432, "hosts": ["a"]}, "name": "x"});
+        merge(&mut dst, json!({"db": {"hosts": ["b"], "user": "eri"}}));
+        assert_eq!(
+            dst,
+            json!({"db": {"port": 5432, "hosts": ["b"], "user": "eri"}, "name": "x"})
+        );
+    }
+
+    #[test]
+    fn load_file_by_extension() {
+        let dir: TestDir = TestDir::new("data-load");
+        let path: PathBuf = dir.join("data.yml");
+        std::fs::write(&path, "port: 80\n").unwrap();
+        assert_eq!(load_file(&path).unwrap(), json!({"port": 80}));
+        assert!(load_file(&dir.join("data")).is_err());
+    }
 }
@@ -0,0 +1,293 @@
+use crate::config::ExecSource;
+use crate::data;
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+
+use serde_json::Value;
+
+/// The timeout of a command, in seconds, if none is configured.
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// The environment variables passed to every command.
+const DEFAULT_ENV: &[&str] = &["PATH", "LANG", "HOME"];
+
+/// The data produced by the commands run so far, by format, directory, command line and
+/// environment.
+static CACHE: Mutex<BTreeMap<Vec<String>, Value>> = Mutex::new(BTreeMap::new());
+
+/// Run a command and parse its output, or get the output of a previous run.
+pub fn run(source: &ExecSource, dir: &Path) -> Result<Value> {
+    let format: &str = source.format.as_deref().unwrap_or("json");
+    let dir: PathBuf = match dir.canonicalize() {
+        Ok(value) => value,
+        Err(e) => {
+            return Err(anyhow!(
+                "failed to run {} in {:?}: {}",
+                source.command,
+                dir,
+                e
+            ))
+        }
+    };
+    let env: Vec<(&str, OsString)> = environment(source);
+
+    let mut key: Vec<String> = vec![
+        format.to_owned(),
+        dir.to_string_lossy().into_owned(),
+        source.command.clone(),
+    ];
+    key.extend(source.args.iter().cloned());
+    for (name, value) in &env {
+        key.push(format!("{}={}", name, value.to_string_lossy()));
+    }
+
+    if let Some(value) = CACHE.lock().unwrap().get(&key) {
+        log::debug!("Using the cached output of {}", source.command);
+        return Ok(value.clone());
+    }
+
+    let output: String = run_command(source, &dir, &env)?;
+    let value: Value = parse_output(&output, format, &source.command)?;
+
+    CACHE.lock().unwrap().insert(key, value.clone());
+    Ok(value)
+}
+
+/// Parse the output of a command in a format.
+fn parse_output(output: &str, format: &str, command: &str) -> Result<Value> {
+    match format {
+        "text" => Ok(Value::String(output.trim().to_owned())),
+        _ => match data::parse(output, format) {
+            Ok(value) => Ok(value),
+            Err(e) => Err(anyhow!("failed to parse the output of {}: {}", command, e)),
+        },
+    }
+}
+
+/// Get the environment variables passed to a command that are set.
+fn environment(source: &ExecSource) -> Vec<(&str, OsString)> {
+    let mut env: Vec<(&str, OsString)> = Vec::new();
+    for name in DEFAULT_ENV
+        .iter()
+        .copied()
+        .chain(source.env.iter().map(String::as_str))
+    {
+        if let Some(value) = std::env::var_os(name) {
+            env.push((name, value));
+        }
+    }
+    env
+}
+
+/// Run a command with a restricted environment and get its standard output.
+///
+/// The command runs in its own process group, which is killed along with the processes the
+/// command started if it does not finish in time, or if they keep its output open.
+fn run_command(source: &ExecSource, dir: &Path, env: &[(&str, OsString)]) -> Result<String> {
+    let mut command: Command = Command::new(&source.command);
+    command
+        .args(&source.args)
+        .current_dir(dir)
+        .env_clear()
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+
+    log::debug!("Running {} {}", source.command, source.args.join(" "));
+    let mut child: Child = match command.spawn() {
+        Ok(value) => value,
+        Err(e) => return Err(anyhow!("failed to run {}: {}", source.command, e)),
+    };
+    // the output is read while waiting, so that the command never blocks on a full pipe
+    let stdout: Receiver<Vec<u8>> = read_to_end(child.stdout.take().unwrap());
+    let stderr: Receiver<Vec<u8>> = read_to_end(child.stderr.take().unwrap());
+
+    let timeout: Duration = Duration::from_secs(source.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let deadline: Instant = Instant::now() + timeout;
+    let status: ExitStatus = loop {
+        if let Some(value) = child.try_wait()? {
+            break value;
+        }
+        if Instant::now() >= deadline {
+            kill_group(&child)?;
+            child.wait()?;
+            return Err(anyhow!(
+                "{} did not finish within {} seconds",
+                source.command,
+                timeout.as_secs()
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+
+    // processes started by the command can keep its output open after it exits
+    let (stdout, stderr): (Vec<u8>, Vec<u8>) =
+        match (receive(&stdout, deadline), receive(&stderr, deadline)) {
+            (Some(stdout), Some(stderr)) => (stdout, stderr),
+            _ => {
+                kill_group(&child)?;
+                return Err(anyhow!(
+                    "the output of {} was not closed within {} seconds",
+                    source.command,
+                    timeout.as_secs()
+                ));
+            }
+        };
+    if !status.success() {
+        return Err(anyhow!(
+            "{} failed with {}: {}",
+            source.command,
+            status,
+            String::from_utf8_lossy(&stderr).trim()
+        ));
+    }
+    match String::from_utf8(stdout) {
+        Ok(value) => Ok(value),
+        Err(_) => Err(anyhow!(
+            "the output of {} is not valid UTF-8",
+            source.command
+        )),
+    }
+}
+
+/// Kill the process group of a command.
+fn kill_group(child: &Child) -> Result<()> {
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+        let e: std::io::Error = std::io::Error::last_os_error();
+        // the processes of the group could have exited already
+        if e.raw_os_error() != Some(libc::ESRCH) {
+            return Err(anyhow!(
+                "failed to kill the process group {}: {}",
+                child.id(),
+                e
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Read everything from a reader in a separate thread.
+fn read_to_end<R: Read + Send + 'static>(mut reader: R) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf: Vec<u8> = Vec::new();
+        let _ = reader.read_to_end(&mut buf);
+        let _ = sender.send(buf);
+    });
+    receiver
+}
+
+/// Wait for everything read by a thread, until a deadline.
+fn receive(receiver: &Receiver<Vec<u8>>, deadline: Instant) -> Option<Vec<u8>> {
+    receiver
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    use serde_json::json;
+
+    /// Create a source running a shell script.
+    fn source(script: &str, format: Option<&str>) -> ExecSource {
+        ExecSource {
+            command: "sh".to_owned(),
+            args: vec!["-c".to_owned(), script.to_owned()],
+            format: format.map(str::to_owned),
+            timeout: Some(5),
+            env: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parse_output_formats() {
+        assert_eq!(
+            parse_output(" value\n", "text", "cmd").unwrap(),
+            json!("value")
+        );
+        assert_eq!(
+            parse_output("a: 1\n", "yaml", "cmd").unwrap(),
+            json!({"a": 1})
+        );
+        assert!(parse_output("{", "json", "cmd").is_err());
+    }
+
+    #[test]
+    fn run_json_command() {
+        let value: Value = run(
+            &source("echo '{\"count\": 2}'", None),
+            &TestDir::new("exec-json"),
+        )
+        .unwrap();
+        assert_eq!(value, json!({"count": 2}));
+    }
+
+    #[test]
+    fn run_is_cached_per_directory() {
+        let source: ExecSource = source("basename \"$PWD\"", Some("text"));
+        let (dir_a, dir_b) = (TestDir::new("exec-cache"), TestDir::new("exec-cache"));
+        let first: Value = run(&source, &dir_a).unwrap();
+        let second: Value = run(&source, &dir_b).unwrap();
+        assert_eq!(first, json!(dir_a.file_name().unwrap().to_str().unwrap()));
+        assert_eq!(second, json!(dir_b.file_name().unwrap().to_str().unwrap()));
+    }
+
+    #[test]
+    fn run_failing_command() {
+        let e: String = run(
+            &source("echo oops >&2; exit 3", None),
+            &TestDir::new("exec-fail"),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(e.contains("oops"), "{}", e);
+    }
+
+    #[test]
+    fn run_timeout() {
+        let mut source: ExecSource = source("sleep 5", None);
+        source.timeout = Some(0);
+        assert!(run(&source, &TestDir::new("exec-timeout")).is_err());
+    }
+
+    #[test]
+    fn run_timeout_with_background_process() {
+        let dir: TestDir = TestDir::new("exec-background");
+        let mut source: ExecSource = source("sleep 10 & echo $! > pid; echo '{}'", None);
+        source.timeout = Some(1);
+        let start: Instant = Instant::now();
+        let e: String = run(&source, &dir).unwrap_err().to_string();
+        assert!(e.contains("was not closed"), "{}", e);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // the background process is killed along with the command
+        let pid: String = std::fs::read_to_string(dir.join("pid")).unwrap();
+        let stat: PathBuf = Path::new("/proc").join(pid.trim()).join("stat");
+        std::thread::sleep(Duration::from_millis(100));
+        if let Ok(value) = std::fs::read_to_string(&stat) {
+            assert!(value.contains(") Z "), "{}", value);
+        }
+    }
+}
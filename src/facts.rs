@@ -0,0 +1,249 @@
+use crate::data;
+
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::path::Path;
+
+use anyhow::Result;
+
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+
+/// Gather the facts about the local host.
+pub fn gather() -> Result<Value> {
+    let hostname: String = hostname()?;
+    let fqdn: String = fqdn(&hostname).unwrap_or_else(|| hostname.clone());
+    let interfaces: BTreeMap<String, Vec<IpAddr>> = interfaces()?;
+
+    let mut ipv4_addresses: Vec<String> = Vec::new();
+    let mut ipv6_addresses: Vec<String> = Vec::new();
+    let mut interfaces_value: Map<String, Value> = Map::new();
+    for (name, addresses) in &interfaces {
+        let mut ipv4: Vec<String> = Vec::new();
+        let mut ipv6: Vec<String> = Vec::new();
+        for address in addresses {
+            match address {
+                IpAddr::V4(value) => {
+                    ipv4.push(value.to_string());
+                    if !value.is_loopback() && !value.is_link_local() {
+                        ipv4_addresses.push(value.to_string());
+                    }
+                }
+                IpAddr::V6(value) => {
+                    ipv6.push(value.to_string());
+                    // fe80::/10 is link local
+                    if !value.is_loopback() && value.segments()[0] & 0xffc0 != 0xfe80 {
+                        ipv6_addresses.push(value.to_string());
+                    }
+                }
+            }
+        }
+        interfaces_value.insert(name.clone(), json!({ "ipv4": ipv4, "ipv6": ipv6 }));
+    }
+
+    let (kernel_name, kernel_release, kernel_version, architecture) = uname()?;
+
+    Ok(json!({
+        "hostname": hostname,
+        "fqdn": fqdn,
+        "ipv4": ipv4_addresses.first(),
+        "ipv6": ipv6_addresses.first(),
+        "ipv4_addresses": ipv4_addresses,
+        "ipv6_addresses": ipv6_addresses,
+        "interfaces": interfaces_value,
+        "cpu_count": unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) },
+        "memory_total": memory_total()?,
+        "os": os_release(Path::new("/"))?,
+        "kernel": {
+            "name": kernel_name,
+            "release": kernel_release,
+            "version": kernel_version,
+        },
+        "architecture": architecture,
+    }))
+}
+
+/// Load the facts from a data file instead of gathering them.
+pub fn load(path: &Path) -> Result<Value> {
+    let facts: Value = data::load_file(path)?;
+    if !facts.is_object() {
+        return Err(anyhow!("facts file {:?} does not contain an object", path));
+    }
+    Ok(facts)
+}
+
+/// Get the host name.
+pub(crate) fn hostname() -> Result<String> {
+    let mut buf: Vec<u8> = vec![0; 256];
+    let ret_val: libc::c_int =
+        unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret_val == -1 {
+        return Err(anyhow!("gethostname: {}", std::io::Error::last_os_error()));
+    }
+    let len: usize = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    buf.truncate(len);
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Get the fully qualified domain name of a host name.
+fn fqdn(hostname: &str) -> Option<String> {
+    let cstr_hostname: CString = CString::new(hostname).ok()?;
+    let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
+    hints.ai_flags = libc::AI_CANONNAME;
+    hints.ai_family = libc::AF_UNSPEC;
+    let mut res: *mut libc::addrinfo = std::ptr::null_mut();
+
+    let ret_val: libc::c_int =
+        unsafe { libc::getaddrinfo(cstr_hostname.as_ptr(), std::ptr::null(), &hints, &mut res) };
+    if ret_val != 0 || res.is_null() {
+        return None;
+    }
+    let canonname: *const libc::c_char = unsafe { (*res).ai_canonname };
+    let fqdn: Option<String> = if canonname.is_null() {
+        None
+    } else {
+        Some(
+            unsafe { CStr::from_ptr(canonname) }
+                .to_string_lossy()
+                .into_owned(),
+        )
+    };
+    unsafe { libc::freeaddrinfo(res) };
+    fqdn
+}
+
+/// Get the addresses of the network interfaces, by interface name.
+fn interfaces() -> Result<BTreeMap<String, Vec<IpAddr>>> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } == -1 {
+        return Err(anyhow!("getifaddrs: {}", std::io::Error::last_os_error()));
+    }
+
+    let mut interfaces: BTreeMap<String, Vec<IpAddr>> = BTreeMap::new();
+    let mut current: *mut libc::ifaddrs = ifaddrs;
+    while !current.is_null() {
+        let ifaddr: &libc::ifaddrs = unsafe { &*current };
+        current = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null() {
+            continue;
+        }
+        let name: String = unsafe { CStr::from_ptr(ifaddr.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let address: IpAddr = match i32::from(unsafe { (*ifaddr.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let sockaddr: &libc::sockaddr_in =
+                    unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sockaddr.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let sockaddr: &libc::sockaddr_in6 =
+                    unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(sockaddr.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        interfaces.entry(name).or_default().push(address);
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(interfaces)
+}
+
+/// Get the total amount of memory, in bytes.
+fn memory_total() -> Result<u64> {
+    let meminfo: String = std::fs::read_to_string("/proc/meminfo")?;
+    for line in meminfo.lines() {
+        if let Some(value) = line.strip_prefix("MemTotal:") {
+            let kilobytes: u64 = value.trim().trim_end_matches("kB").trim().parse()?;
+            return Ok(kilobytes * 1024);
+        }
+    }
+    Err(anyhow!("MemTotal not found in /proc/meminfo"))
+}
+
+/// Get the operating system identification data of a root directory, with lower case keys.
+/// The data is empty if the root does not have an os-release file(e.g. minimal containers).
+fn os_release(root: &Path) -> Result<Map<String, Value>> {
+    for path in &["etc/os-release", "usr/lib/os-release"] {
+        match std::fs::read_to_string(root.join(path)) {
+            Ok(value) => return Ok(parse_os_release(&value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(anyhow!("failed to read {:?}: {}", root.join(path), e)),
+        }
+    }
+    log::debug!("No os-release file found in {:?}", root);
+    Ok(Map::new())
+}
+
+/// Parse the contents of an os-release file, with lower case keys.
+fn parse_os_release(os_release: &str) -> Map<String, Value> {
+    let mut os: Map<String, Value> = Map::new();
+    for line in os_release.lines() {
+        let line: &str = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(index) = line.find('=') {
+            let key: String = line[..index].to_lowercase();
+            let value: &str = line[index + 1..].trim_matches(|c| c == '"' || c == '\'');
+            os.insert(key, Value::String(value.to_owned()));
+        }
+    }
+    os
+}
+
+/// Get the kernel name, release and version, and the machine architecture.
+fn uname() -> Result<(String, String, String, String)> {
+    let mut utsname: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut utsname) } == -1 {
+        return Err(anyhow!("uname: {}", std::io::Error::last_os_error()));
+    }
+    let field = |value: &[libc::c_char]| -> String {
+        unsafe { CStr::from_ptr(value.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    Ok((
+        field(&utsname.sysname),
+        field(&utsname.release),
+        field(&utsname.version),
+        field(&utsname.machine),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    #[test]
+    fn parse_os_release_keys() {
+        let os: Map<String, Value> =
+            parse_os_release("# comment\nID=debian\nVERSION_ID=\"12\"\n\nPRETTY_NAME='Debian'\n");
+        assert_eq!(os["id"], "debian");
+        assert_eq!(os["version_id"], "12");
+        assert_eq!(os["pretty_name"], "Debian");
+        assert_eq!(os.len(), 3);
+    }
+
+    #[test]
+    fn os_release_of_root() {
+        let root: TestDir = TestDir::new("facts-os-release");
+        assert!(os_release(&root).unwrap().is_empty());
+
+        std::fs::create_dir_all(root.join("usr/lib")).unwrap();
+        std::fs::write(root.join("usr/lib/os-release"), "ID=alpine\n").unwrap();
+        assert_eq!(os_release(&root).unwrap()["id"], "alpine");
+
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc/os-release"), "ID=debian\n").unwrap();
+        assert_eq!(os_release(&root).unwrap()["id"], "debian");
+    }
+}
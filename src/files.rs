@@ -0,0 +1,275 @@
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::File;
+use std::fs::Permissions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+/// The maximum number of symbolic links followed while joining a path to a root.
+const MAX_LINKS: usize = 40;
+
+/// The files that templates can access, and the ones they have accessed.
+///
+/// Templates can only access files inside the namespace directory and the allowed
+/// directories of the eri configuration, symbolic links being resolved first.
+#[derive(Debug)]
+pub struct Files {
+    base_path: PathBuf,
+    allowed_dirs: Vec<PathBuf>,
+    accessed: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl Files {
+    /// Create the files of a namespace directory.
+    /// Relative allowed directories are relative to the current directory.
+    pub fn new(base_path: PathBuf, allow_dirs: &[String]) -> Result<Self> {
+        let current_dir_path: PathBuf = std::env::current_dir()?;
+        let base_path: PathBuf = base_path.canonicalize()?;
+        let mut allowed_dirs: Vec<PathBuf> = vec![base_path.clone()];
+        for dir in allow_dirs {
+            match normalize(&current_dir_path.join(dir)).canonicalize() {
+                Ok(value) => allowed_dirs.push(value),
+                Err(e) => return Err(anyhow!("allowed directory {} not found: {}", dir, e)),
+            }
+        }
+        Ok(Files {
+            base_path,
+            allowed_dirs,
+            accessed: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    /// Read a file.
+    pub fn read(&self, path: &str) -> Result<String> {
+        let path: PathBuf = self.resolve(path)?;
+        self.record(&path);
+        match std::fs::read_to_string(&path) {
+            Ok(value) => Ok(value),
+            Err(e) => Err(anyhow!("failed to read {:?}: {}", path, e)),
+        }
+    }
+
+    /// Check whether a file exists.
+    pub fn exists(&self, path: &str) -> Result<bool> {
+        let path: PathBuf = self.resolve(path)?;
+        self.record(&path);
+        Ok(path.is_file())
+    }
+
+    /// Get the files matching a pattern, sorted.
+    /// Files inside the namespace directory are relative to it.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<String>> {
+        let full_pattern: PathBuf = self.resolve(pattern)?;
+        let full_pattern: &str = match full_pattern.to_str() {
+            Some(value) => value,
+            None => return Err(anyhow!("invalid path pattern: {:?}", full_pattern)),
+        };
+
+        let mut files: Vec<String> = Vec::new();
+        for path in glob::glob(full_pattern)? {
+            let path: PathBuf = path?;
+            if !path.is_file() || self.check(&path).is_err() {
+                continue;
+            }
+            self.record(&path);
+            let name: &Path = path.strip_prefix(&self.base_path).unwrap_or(&path);
+            match name.to_str() {
+                Some(value) => files.push(value.to_owned()),
+                None => return Err(anyhow!("invalid file name: {:?}", path)),
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Get the files accessed so far.
+    pub fn accessed(&self) -> Vec<PathBuf> {
+        self.accessed.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Resolve a path relative to the namespace directory, checking that it's allowed.
+    fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let path: PathBuf = normalize(&self.base_path.join(path));
+        self.check(&path)?;
+        Ok(path)
+    }
+
+    /// Check that a path is inside the allowed directories, including its target if it's a
+    /// symbolic link.
+    fn check(&self, path: &Path) -> Result<()> {
+        let allowed = |path: &Path| self.allowed_dirs.iter().any(|dir| path.starts_with(dir));
+        if !allowed(path) {
+            return Err(anyhow!("{:?} is outside of the allowed directories", path));
+        }
+        if let Ok(target) = path.canonicalize() {
+            if !allowed(&target) {
+                return Err(anyhow!(
+                    "{:?} links to {:?}, which is outside of the allowed directories",
+                    path,
+                    target
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Record that a file was accessed.
+    fn record(&self, path: &Path) {
+        self.accessed.lock().unwrap().insert(path.to_path_buf());
+    }
+}
+
+/// Remove the `.` and `..` components of a path without accessing the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result: PathBuf = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            component => result.push(component),
+        }
+    }
+    result
+}
+
+/// Join a path to a root directory as if the root was `/`(e.g. a chroot), so that neither
+/// `..` nor symbolic links lead outside of the root.
+/// Absolute symbolic link targets are resolved inside the root.
+pub fn join_root(root: &Path, path: &Path) -> Result<PathBuf> {
+    let mut result: PathBuf = root.to_path_buf();
+    let mut components: Vec<OsString> = root_components(path);
+    components.reverse();
+    let mut links: usize = 0;
+    while let Some(component) = components.pop() {
+        if component == ".." {
+            if result != root {
+                result.pop();
+            }
+            continue;
+        }
+        let next: PathBuf = result.join(&component);
+        match std::fs::symlink_metadata(&next) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                links += 1;
+                if links > MAX_LINKS {
+                    return Err(anyhow!("too many symbolic links in {:?}", path));
+                }
+                let target: PathBuf = std::fs::read_link(&next)?;
+                if target.is_absolute() {
+                    result = root.to_path_buf();
+                }
+                components.extend(root_components(&target).into_iter().rev());
+            }
+            _ => result = next,
+        }
+    }
+    Ok(result)
+}
+
+/// Replace a file at once, so that it's never left half written, with some permissions and
+/// optionally some owner(uid and gid).
+///
+/// The contents are written to a temporary file next to it, readable only by the current
+/// user until it has its ownership and permissions, so that they are never exposed. A
+/// leftover temporary file is removed rather than reused, since it could have broader
+/// permissions.
+pub fn atomic_write(
+    path: &Path,
+    contents: &[u8],
+    mode: u32,
+    owner: Option<(u32, u32)>,
+) -> Result<()> {
+    let file_name: String = match path.file_name() {
+        Some(value) => value.to_string_lossy().into_owned(),
+        None => return Err(anyhow!("cannot write {:?}, it's not a file", path)),
+    };
+    let tmp_path: PathBuf = path.with_file_name(format!(".{}.eri-tmp", file_name));
+    if tmp_path.exists() {
+        std::fs::remove_file(&tmp_path)?;
+    }
+    let mut file: File = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(contents)?;
+    if let Some((uid, gid)) = owner {
+        std::os::unix::fs::fchown(&file, Some(uid), Some(gid))?;
+    }
+    file.set_permissions(Permissions::from_mode(mode))?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Get the names and `..` components of a path.
+fn root_components(path: &Path) -> Vec<OsString> {
+    let mut components: Vec<OsString> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(value) => components.push(value.to_owned()),
+            Component::ParentDir => components.push(OsString::from("..")),
+            _ => {}
+        }
+    }
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    #[test]
+    fn join_root_stays_inside() {
+        let root: TestDir = TestDir::new("files-root");
+        std::fs::create_dir_all(root.join("etc/redis")).unwrap();
+        for (link, target) in &[("etc/absolute", "/etc/redis"), ("etc/relative", "../../..")] {
+            std::os::unix::fs::symlink(target, root.join(link)).unwrap();
+        }
+
+        let join = |path: &str| join_root(&root, Path::new(path)).unwrap();
+        assert_eq!(
+            join("/etc/redis/redis.conf"),
+            root.join("etc/redis/redis.conf")
+        );
+        assert_eq!(join("/../../etc/passwd"), root.join("etc/passwd"));
+        assert_eq!(
+            join("/etc/absolute/redis.conf"),
+            root.join("etc/redis/redis.conf")
+        );
+        assert_eq!(join("/etc/relative/etc/passwd"), root.join("etc/passwd"));
+    }
+
+    #[test]
+    fn atomic_write_replaces_file() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir: TestDir = TestDir::new("files-atomic");
+        let path: PathBuf = dir.join("state.json");
+        std::fs::write(&path, "old").unwrap();
+        std::fs::write(dir.join(".state.json.eri-tmp"), "leftover").unwrap();
+
+        atomic_write(&path, b"new", 0o640, None).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o7777, 0o640);
+        assert!(!dir.join(".state.json.eri-tmp").exists());
+    }
+
+    #[test]
+    fn join_root_link_loop() {
+        let root: TestDir = TestDir::new("files-loop");
+        std::os::unix::fs::symlink("/loop", root.join("loop")).unwrap();
+        assert!(join_root(&root, Path::new("/loop/file")).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use crate::config::map_group;
+use crate::config::map_mode;
+use crate::config::map_user;
+use crate::template::Kind;
+
+use anyhow::Result;
+
+use uclicious::Priority;
+use uclicious::DEFAULT_DUPLICATE_STRATEGY;
+
+use uclicious_derive::*;
+
+use umask::Mode;
+
+use users::Group;
+use users::User;
+
+/// The line that opens a front matter block.
+const OPENING: &str = "---eri";
+/// The line that closes a front matter block.
+const CLOSING: &str = "---";
+
+/// Metadata declared at the beginning of a template, between a `---eri` and a `---` line.
+///
+/// The opening line is not a plain `---`, as front matter blocks usually are, so that
+/// templates of YAML documents, which often start with one, are not mistaken for front
+/// matter and lose their first document.
+///
+/// The block is parsed as UCL, so both `key = value` and the YAML-like
+/// `key: value` syntax are accepted. The same metadata can be declared for a
+/// template in the `templates` section of the `eri` block of its namespace.
+#[derive(Clone, Debug, Default, Uclicious)]
+pub struct FrontMatter {
+    /// The name of the rendered file, which is itself rendered using the template data.
+    /// By default, it's the name of the template file.
+    #[ucl(default)]
+    pub filename: Option<String>,
+    /// The user who should own the rendered template.
+    #[ucl(default, map = "map_user")]
+    pub user: Option<User>,
+    /// The group who should own the rendered template.
+    #[ucl(default, map = "map_group")]
+    pub group: Option<Group>,
+    /// The permissions that should be applied to the rendered template.
+    #[ucl(default, map = "map_mode")]
+    pub permissions: Option<Mode>,
+    /// The way the file is exported: "template" to render it or "copy" to copy it as is.
+    /// By default, files ending in `.tmpl` or `.hbs` are templates, other files are
+    /// templates unless they are not valid UTF-8.
+    #[ucl(default)]
+    pub kind: Option<String>,
+    /// The escape mode used when rendering expressions: "html" or "none".
+    /// By default, it's "html".
+    #[ucl(default)]
+    pub escape: Option<String>,
+    /// A condition that must hold for the template to be rendered(e.g. `vault.tls`).
+    /// It accepts anything that can follow `#if` in a template.
+    #[ucl(default)]
+    pub when: Option<String>,
+    /// A data key holding a list(e.g. `nginx.vhosts`). When set, the template is
+    /// rendered once for every element of the list, with the keys of the element merged
+    /// into the data, or with the element available under `item` if it's not an object.
+    #[ucl(default)]
+    pub foreach: Option<String>,
+    /// Whether the template should not be written when it renders empty.
+    #[ucl(default)]
+    pub skip_empty: Option<bool>,
+    /// The data keys that must be present for the template to be rendered.
+    #[ucl(default)]
+    pub requires: Vec<String>,
+}
+
+impl FrontMatter {
+    /// Parse a front matter block.
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut builder = FrontMatter::builder()?;
+        builder.add_chunk_full(src, Priority::default(), DEFAULT_DUPLICATE_STRATEGY)?;
+
+        match builder.build() {
+            Ok(value) => Ok(value),
+            Err(e) => Err(anyhow!("failed to build front matter: {}", e)),
+        }
+    }
+
+    /// Check that the values of this front matter are valid.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(kind) = &self.kind {
+            if kind != "template" && kind != "copy" {
+                return Err(anyhow!(
+                    "kind should be either \"template\" or \"copy\", not \"{}\"",
+                    kind
+                ));
+            }
+        }
+        if let Some(escape) = &self.escape {
+            if escape != "html" && escape != "none" {
+                return Err(anyhow!(
+                    "escape should be either \"html\" or \"none\", not \"{}\"",
+                    escape
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge another front matter into this one, the values of the other one taking precedence.
+    pub fn merge(&mut self, other: FrontMatter) {
+        if other.filename.is_some() {
+            self.filename = other.filename;
+        }
+        if other.user.is_some() {
+            self.user = other.user;
+        }
+        if other.group.is_some() {
+            self.group = other.group;
+        }
+        if other.permissions.is_some() {
+            self.permissions = other.permissions;
+        }
+        if other.kind.is_some() {
+            self.kind = other.kind;
+        }
+        if other.escape.is_some() {
+            self.escape = other.escape;
+        }
+        if other.when.is_some() {
+            self.when = other.when;
+        }
+        if other.foreach.is_some() {
+            self.foreach = other.foreach;
+        }
+        if other.skip_empty.is_some() {
+            self.skip_empty = other.skip_empty;
+        }
+        for key in other.requires {
+            if !self.requires.contains(&key) {
+                self.requires.push(key);
+            }
+        }
+    }
+
+    /// Get the kind of the template, if declared.
+    pub fn kind(&self) -> Option<Kind> {
+        match self.kind.as_deref() {
+            Some("template") => Some(Kind::Template),
+            Some("copy") => Some(Kind::Copy),
+            _ => None,
+        }
+    }
+
+    /// Get the escape function used when rendering expressions.
+    pub fn escape_fn(&self) -> fn(&str) -> String {
+        match self.escape.as_deref() {
+            Some("none") => handlebars::no_escape,
+            _ => handlebars::html_escape,
+        }
+    }
+}
+
+/// Split a template source into its front matter block and its content.
+pub fn split(src: &str) -> Result<(Option<&str>, &str)> {
+    let mut lines = src.split_inclusive('\n');
+    let start: usize = match lines.next() {
+        Some(line) if line.trim_end() == OPENING => line.len(),
+        _ => return Ok((None, src)),
+    };
+
+    let mut end: usize = start;
+    for line in lines {
+        if line.trim_end() == CLOSING {
+            return Ok((Some(&src[start..end]), &src[end + line.len()..]));
+        }
+        end += line.len();
+    }
+
+    Err(anyhow!("front matter block is not closed with {}", CLOSING))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_front_matter() {
+        let src: &str = "---eri\nfilename: a.conf\n---\ncontents\n";
+        let (front_matter, contents) = split(src).unwrap();
+        assert_eq!(front_matter, Some("filename: a.conf\n"));
+        assert_eq!(contents, "contents\n");
+    }
+
+    #[test]
+    fn split_without_front_matter() {
+        let src: &str = "contents\n---\n";
+        assert_eq!(split(src).unwrap(), (None, src));
+    }
+
+    #[test]
+    fn split_keeps_yaml_documents() {
+        let src: &str = "---\nkey: value\n---\nother: value\n";
+        assert_eq!(split(src).unwrap(), (None, src));
+    }
+
+    #[test]
+    fn split_empty_front_matter() {
+        let src: &str = "---eri\r\n---\r\ncontents";
+        assert_eq!(split(src).unwrap(), (Some(""), "contents"));
+    }
+
+    #[test]
+    fn split_unclosed_front_matter() {
+        assert!(split("---eri\nfilename: a.conf\n").is_err());
+    }
+
+    #[test]
+    fn parse_front_matter() {
+        let front_matter: FrontMatter =
+            FrontMatter::parse("filename: a.conf\nkind = copy\nrequires = [a, b]\n").unwrap();
+        assert_eq!(front_matter.filename.as_deref(), Some("a.conf"));
+        assert_eq!(front_matter.kind(), Some(Kind::Copy));
+        assert_eq!(front_matter.requires, vec!["a", "b"]);
+        assert!(front_matter.validate().is_ok());
+    }
+
+    #[test]
+    fn parse_readme_example() {
+        let src: &str = "---eri\nforeach: nginx.sites\nfilename: \"sites/{{name}}.conf\"\npermissions: 640\n---\nserver_name {{name}};\n";
+        let (front_matter, contents) = split(src).unwrap();
+        let front_matter: FrontMatter = FrontMatter::parse(front_matter.unwrap()).unwrap();
+        assert_eq!(front_matter.foreach.as_deref(), Some("nginx.sites"));
+        assert_eq!(
+            front_matter.filename.as_deref(),
+            Some("sites/{{name}}.conf")
+        );
+        assert_eq!(u32::from(front_matter.permissions.unwrap()), 0o640);
+        assert_eq!(contents, "server_name {{name}};\n");
+    }
+}
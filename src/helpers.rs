@@ -0,0 +1,385 @@
+use crate::files::Files;
+use crate::state::State;
+
+use std::sync::Arc;
+
+use handlebars::Context;
+use handlebars::Handlebars;
+use handlebars::Helper;
+use handlebars::HelperDef;
+use handlebars::HelperResult;
+use handlebars::Output;
+use handlebars::RenderContext;
+use handlebars::RenderError;
+use handlebars::ScopedJson;
+
+use rand::rngs::OsRng;
+use rand::Rng;
+use rand::RngCore;
+
+use serde_json::Value;
+
+/// The characters of generated passwords.
+const PASSWORD_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// The characters added to generated passwords that contain symbols.
+const PASSWORD_SYMBOLS: &[u8] = b"!#%+,-.:=@^_~";
+
+/// The length of generated passwords, if none is set.
+const DEFAULT_PASSWORD_LENGTH: u64 = 32;
+
+/// The number of random bytes of generated keys, if none is set.
+const DEFAULT_KEY_BYTES: u64 = 32;
+
+/// A function generating a value using the arguments of a helper.
+type Generator = fn(&Helper) -> Result<String, RenderError>;
+
+/// Register the helpers of a namespace.
+///
+/// - `{{genPassword "name" length=32 symbols=false}}` generates a random password
+/// - `{{genKey "name" bytes=32}}` generates random bytes, encoded using base64(e.g. a gossip key)
+/// - `{{uuid "name"}}` generates a random UUID
+/// - `{{file "path"}}` gets the contents of a file
+/// - `{{fileExists "path"}}` checks whether a file exists
+/// - `{{glob "pattern"}}` gets the files matching a pattern
+/// - `{{env "NAME" default="value"}}` gets the value of an environment variable
+///
+/// Generated values are saved in the state of the namespace under their name, separately
+/// for each host and instance, and the same value is returned on every subsequent render.
+/// Paths are relative to the namespace directory, and the files accessed are recorded as
+/// dependencies of the namespace in the manifest of its export directory.
+pub fn register(handlebars: &mut Handlebars, state: Arc<State>, files: Arc<Files>) {
+    handlebars.register_helper(
+        "genPassword",
+        Box::new(Generate {
+            state: state.clone(),
+            generator: gen_password,
+        }),
+    );
+    handlebars.register_helper(
+        "genKey",
+        Box::new(Generate {
+            state: state.clone(),
+            generator: gen_key,
+        }),
+    );
+    handlebars.register_helper(
+        "uuid",
+        Box::new(Generate {
+            state,
+            generator: gen_uuid,
+        }),
+    );
+    handlebars.register_helper(
+        "file",
+        Box::new(FileHelper {
+            files: files.clone(),
+            function: |files, path| Ok(Value::String(files.read(path)?)),
+        }),
+    );
+    handlebars.register_helper(
+        "fileExists",
+        Box::new(FileHelper {
+            files: files.clone(),
+            function: |files, path| Ok(Value::Bool(files.exists(path)?)),
+        }),
+    );
+    handlebars.register_helper(
+        "glob",
+        Box::new(FileHelper {
+            files,
+            function: |files, pattern| {
+                Ok(Value::Array(
+                    files
+                        .glob(pattern)?
+                        .into_iter()
+                        .map(Value::String)
+                        .collect(),
+                ))
+            },
+        }),
+    );
+    handlebars.register_helper("env", Box::new(Env));
+}
+
+/// A helper returning a value generated once.
+struct Generate {
+    state: Arc<State>,
+    generator: Generator,
+}
+
+impl HelperDef for Generate {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        write_unescaped(self.call_inner(h, r, ctx, rc)?, out)
+    }
+
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let name: &str = match h.param(0).and_then(|param| param.value().as_str()) {
+            Some(value) => value,
+            None => {
+                return Err(RenderError::new(format!(
+                    "{}: the name of the generated value is required",
+                    h.name()
+                )))
+            }
+        };
+        let generator: Generator = self.generator;
+        match self
+            .state
+            .get_or_generate(name, || generator(h).map_err(|e| anyhow!("{}", e)))
+        {
+            Ok(value) => Ok(Some(ScopedJson::Derived(Value::String(value)))),
+            Err(e) => Err(RenderError::new(format!("{}: {}", h.name(), e))),
+        }
+    }
+}
+
+/// A helper accessing files using its first argument.
+struct FileHelper {
+    files: Arc<Files>,
+    function: fn(&Files, &str) -> anyhow::Result<Value>,
+}
+
+impl HelperDef for FileHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        write_unescaped(self.call_inner(h, r, ctx, rc)?, out)
+    }
+
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let path: &str = match h.param(0).and_then(|param| param.value().as_str()) {
+            Some(value) => value,
+            None => {
+                return Err(RenderError::new(format!(
+                    "{}: a path is required",
+                    h.name()
+                )))
+            }
+        };
+        match (self.function)(&self.files, path) {
+            Ok(value) => Ok(Some(ScopedJson::Derived(value))),
+            Err(e) => Err(RenderError::new(format!("{}: {}", h.name(), e))),
+        }
+    }
+}
+
+/// A helper getting the value of an environment variable.
+struct Env;
+
+impl HelperDef for Env {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        write_unescaped(self.call_inner(h, r, ctx, rc)?, out)
+    }
+
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let name: &str = match h.param(0).and_then(|param| param.value().as_str()) {
+            Some(value) => value,
+            None => return Err(RenderError::new("env: a variable name is required")),
+        };
+        let value: String = match (std::env::var(name), h.hash_get("default")) {
+            (Ok(value), _) => value,
+            (Err(_), Some(default)) => default.render(),
+            (Err(_), None) => {
+                return Err(RenderError::new(format!(
+                    "env: {} is not set and has no default",
+                    name
+                )))
+            }
+        };
+        Ok(Some(ScopedJson::Derived(Value::String(value))))
+    }
+}
+
+/// Write the result of a helper as it is, whatever the escape mode of the template, as it's
+/// not a value of the data(e.g. a generated key ending with `=` or a certificate).
+fn write_unescaped(result: Option<ScopedJson>, out: &mut dyn Output) -> HelperResult {
+    if let Some(value) = result {
+        out.write(&value.render())?;
+    }
+    Ok(())
+}
+
+/// Generate a random password.
+fn gen_password(h: &Helper) -> Result<String, RenderError> {
+    let length: u64 = hash_u64(h, "length", DEFAULT_PASSWORD_LENGTH)?;
+    let mut chars: Vec<u8> = PASSWORD_CHARS.to_vec();
+    let symbols: Option<bool> = h
+        .hash_get("symbols")
+        .and_then(|value| value.value().as_bool());
+    if symbols == Some(true) {
+        chars.extend_from_slice(PASSWORD_SYMBOLS);
+    }
+    Ok((0..length)
+        .map(|_| chars[OsRng.gen_range(0..chars.len())] as char)
+        .collect())
+}
+
+/// Generate random bytes, encoded using base64.
+fn gen_key(h: &Helper) -> Result<String, RenderError> {
+    let mut bytes: Vec<u8> = vec![0; hash_u64(h, "bytes", DEFAULT_KEY_BYTES)? as usize];
+    OsRng.fill_bytes(&mut bytes);
+    Ok(base64::encode(bytes))
+}
+
+/// Generate a random UUID.
+fn gen_uuid(_: &Helper) -> Result<String, RenderError> {
+    Ok(uuid::Uuid::new_v4().to_string())
+}
+
+/// Get a positive integer argument of a helper.
+fn hash_u64(h: &Helper, key: &str, default: u64) -> Result<u64, RenderError> {
+    match h.hash_get(key) {
+        Some(value) => match value.value().as_u64() {
+            Some(value) if value > 0 => Ok(value),
+            _ => Err(RenderError::new(format!(
+                "{} should be a positive integer",
+                key
+            ))),
+        },
+        None => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::state::STATE_FILE_NAME;
+    use crate::testing::TestDir;
+
+    /// Create a handlebars object with the generating helpers, keeping their state in a
+    /// directory of the test.
+    fn handlebars(test: &str) -> (Handlebars<'static>, TestDir) {
+        let dir: TestDir = TestDir::new(&format!("helpers-{}", test));
+        let state: Arc<State> = Arc::new(State::new(dir.join(STATE_FILE_NAME), None, None, false));
+        let mut handlebars: Handlebars = Handlebars::new();
+        handlebars.register_helper(
+            "genPassword",
+            Box::new(Generate {
+                state: state.clone(),
+                generator: gen_password,
+            }),
+        );
+        handlebars.register_helper(
+            "genKey",
+            Box::new(Generate {
+                state,
+                generator: gen_key,
+            }),
+        );
+        (handlebars, dir)
+    }
+
+    #[test]
+    fn generated_values_are_not_escaped() {
+        let (handlebars, _dir) = handlebars("escape");
+        let key: String = handlebars
+            .render_template("{{genKey \"gossip\" bytes=16}}", &())
+            .unwrap();
+        assert!(key.ends_with("=="), "{}", key);
+        assert_eq!(base64::decode(&key).unwrap().len(), 16);
+    }
+
+    #[test]
+    fn generated_values_are_kept() {
+        let (handlebars, dir) = handlebars("kept");
+        let template: &str = "{{genPassword \"db\" length=12 symbols=true}}";
+        let password: String = handlebars.render_template(template, &()).unwrap();
+        assert_eq!(password.chars().count(), 12);
+        assert_eq!(handlebars.render_template(template, &()).unwrap(), password);
+
+        // the value is read back from the state file
+        let state: State = State::new(dir.join(STATE_FILE_NAME), None, None, false);
+        let value: String = state
+            .get_or_generate("db", || Err(anyhow!("db should not be generated again")))
+            .unwrap();
+        assert_eq!(value, password);
+    }
+
+    #[test]
+    fn file_helpers() {
+        let dir: TestDir = TestDir::new("helpers-files");
+        std::fs::create_dir_all(dir.join("certs")).unwrap();
+        std::fs::write(dir.join("certs/ca.pem"), "<ca & key=>").unwrap();
+        let files: Arc<Files> = Arc::new(Files::new(dir.to_path_buf(), &[]).unwrap());
+        let state: Arc<State> = Arc::new(State::new(dir.join("state.json"), None, None, false));
+        let mut handlebars: Handlebars = Handlebars::new();
+        register(&mut handlebars, state, files.clone());
+
+        let render = |template: &str| handlebars.render_template(template, &()).unwrap();
+        assert_eq!(render("{{file \"certs/ca.pem\"}}"), "<ca & key=>");
+        assert_eq!(render("{{fileExists \"certs/missing.pem\"}}"), "false");
+        assert_eq!(
+            render("{{#each (glob \"certs/*.pem\")}}{{this}}{{/each}}"),
+            "certs/ca.pem"
+        );
+        assert!(handlebars
+            .render_template("{{file \"../outside\"}}", &())
+            .is_err());
+        assert_eq!(
+            files.accessed(),
+            vec![dir.join("certs/ca.pem"), dir.join("certs/missing.pem")]
+        );
+    }
+
+    #[test]
+    fn env_helper() {
+        std::env::set_var("ERI_HELPERS_TEST", "a&b");
+        let mut handlebars: Handlebars = Handlebars::new();
+        handlebars.register_helper("env", Box::new(Env));
+        let render = |template: &str| handlebars.render_template(template, &()).ok();
+        assert_eq!(render("{{env \"ERI_HELPERS_TEST\"}}").unwrap(), "a&b");
+        assert_eq!(
+            render("{{env \"ERI_HELPERS_MISSING\" default=\"x\"}}").unwrap(),
+            "x"
+        );
+        assert!(render("{{env \"ERI_HELPERS_MISSING\"}}").is_none());
+    }
+
+    #[test]
+    fn generate_requires_name() {
+        let (handlebars, _dir) = handlebars("name");
+        assert!(handlebars.render_template("{{genKey}}", &()).is_err());
+        assert!(handlebars
+            .render_template("{{genKey \"k\" bytes=0}}", &())
+            .is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::config;
+use crate::config::map_namespace;
+use crate::config::map_sorted;
+use crate::data;
+
+use std::collections::BTreeMap;
+use std::path::Component;
+use std::path::Path;
+
+use anyhow::Result;
+
+use serde_json::Map;
+use serde_json::Value;
+
+use uclicious::Priority;
+use uclicious::DEFAULT_DUPLICATE_STRATEGY;
+
+use uclicious_derive::*;
+
+/// The name of the group every host is part of.
+const ALL_GROUP: &str = "all";
+
+/// A host of an inventory.
+#[derive(Clone, Debug, Uclicious)]
+pub struct InventoryHost {
+    /// The groups of the host, whose data is layered in order.
+    #[ucl(default)]
+    pub groups: Vec<String>,
+    /// The data of the host, by namespace, layered over the data of its groups.
+    #[ucl(default, map = "map_namespace")]
+    pub vars: Map<String, Value>,
+}
+
+/// A group of hosts of an inventory.
+#[derive(Clone, Debug, Uclicious)]
+pub struct InventoryGroup {
+    /// The data of the hosts of the group, by namespace.
+    #[ucl(default, map = "map_namespace")]
+    pub vars: Map<String, Value>,
+}
+
+/// An inventory of the hosts the namespaces are rendered for.
+#[derive(Clone, Debug, Uclicious)]
+pub struct Inventory {
+    /// The hosts, by host name.
+    #[ucl(default, map = "map_sorted")]
+    pub hosts: BTreeMap<String, InventoryHost>,
+    /// The groups of hosts, by group name.
+    /// The data of the `all` group is used for every host.
+    #[ucl(default, map = "map_sorted")]
+    pub groups: BTreeMap<String, InventoryGroup>,
+}
+
+/// A host the namespaces are rendered for.
+#[derive(Clone, Debug)]
+pub struct Host {
+    pub name: String,
+    pub groups: Vec<String>,
+    /// The data of the host, by namespace, with the data of its groups.
+    pub vars: Map<String, Value>,
+}
+
+/// Load the hosts of an inventory file.
+pub fn load(path: &Path) -> Result<Vec<Host>> {
+    let inventory_string: String = match std::fs::read_to_string(path) {
+        Ok(value) => value,
+        Err(e) => return Err(anyhow!("failed to read inventory {:?}: {}", path, e)),
+    };
+    let mut builder = Inventory::builder()?;
+    for (name, value) in config::ucl_variables(None)? {
+        builder.register_variable(name, value);
+    }
+    builder.set_filevars(path, true)?;
+    builder.add_chunk_full(
+        config::resolve_includes(&inventory_string, path.parent().unwrap_or(Path::new("."))),
+        Priority::default(),
+        DEFAULT_DUPLICATE_STRATEGY,
+    )?;
+    let inventory: Inventory = match builder.build() {
+        Ok(value) => value,
+        Err(e) => return Err(anyhow!("failed to build inventory {:?}: {}", path, e)),
+    };
+    if inventory.hosts.is_empty() {
+        return Err(anyhow!("inventory {:?} has no hosts", path));
+    }
+
+    let mut hosts: Vec<Host> = Vec::new();
+    for (name, host) in &inventory.hosts {
+        // the output tree of a host is a directory named after it
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => {}
+            _ => return Err(anyhow!("invalid host name {:?}", name)),
+        }
+
+        let mut groups: Vec<String> = Vec::new();
+        if inventory.groups.contains_key(ALL_GROUP) {
+            groups.push(ALL_GROUP.to_owned());
+        }
+        for group in &host.groups {
+            if !inventory.groups.contains_key(group) {
+                return Err(anyhow!(
+                    "host {} is part of group {}, which is missing",
+                    name,
+                    group
+                ));
+            }
+            if !groups.contains(group) {
+                groups.push(group.clone());
+            }
+        }
+
+        let mut vars: Value = Value::Object(Map::new());
+        for group in &groups {
+            data::merge(
+                &mut vars,
+                Value::Object(inventory.groups[group].vars.clone()),
+            );
+        }
+        data::merge(&mut vars, Value::Object(host.vars.clone()));
+        let vars: Map<String, Value> = match vars {
+            Value::Object(value) => value,
+            _ => unreachable!(),
+        };
+
+        hosts.push(Host {
+            name: name.clone(),
+            groups,
+            vars,
+        });
+    }
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    use serde_json::json;
+
+    /// Load an inventory written to a temporary file.
+    fn load_inventory(test: &str, src: &str) -> Result<Vec<Host>> {
+        let dir: TestDir = TestDir::new(&format!("inventory-{}", test));
+        std::fs::write(dir.join("hosts.ucl"), src).unwrap();
+        load(&dir.join("hosts.ucl"))
+    }
+
+    #[test]
+    fn load_layers_group_data() {
+        let hosts: Vec<Host> = load_inventory(
+            "layers",
+            r#"
+groups {
+    all { vars { redis { port = 6379; maxmemory = "1g"; } } }
+    cache { vars { redis { maxmemory = "4g"; role = "cache"; } } }
+    db {}
+}
+hosts {
+    web1 {}
+    cache1 { groups = ["cache", "db", "cache"]; vars { redis { port = 6380; } } }
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(hosts.len(), 2);
+
+        assert_eq!(hosts[0].name, "cache1");
+        assert_eq!(hosts[0].groups, ["all", "cache", "db"]);
+        assert_eq!(
+            hosts[0].vars["redis"],
+            json!({"port": 6380, "maxmemory": "4g", "role": "cache"})
+        );
+
+        assert_eq!(hosts[1].name, "web1");
+        assert_eq!(hosts[1].groups, ["all"]);
+        assert_eq!(
+            hosts[1].vars["redis"],
+            json!({"port": 6379, "maxmemory": "1g"})
+        );
+    }
+
+    #[test]
+    fn load_invalid() {
+        assert!(load_inventory("missing-group", "hosts { web1 { groups = [\"db\"]; } }").is_err());
+        assert!(load_inventory("host-name", "hosts { \"../web1\" {} }").is_err());
+        assert!(load_inventory("empty", "").is_err());
+        assert!(load_inventory("groups-only", "groups { all {} }").is_err());
+        assert!(load(Path::new("/nonexistent/hosts.ucl")).is_err());
+    }
+}
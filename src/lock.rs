@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+/// The name of the lock file, inside the directory of the eri configuration.
+pub const LOCK_FILE_NAME: &str = ".eri.lock";
+
+/// An advisory lock preventing runs of eri that change files from overlapping.
+///
+/// The lock file holds the PID of the run holding the lock. The lock is released when it's
+/// dropped or when the process exits.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Acquire the lock of a lock file, waiting for it to be released if asked to.
+    pub fn acquire(path: &Path, wait: bool) -> Result<Self> {
+        let mut file: File = match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // the PID of the run holding the lock is kept until the lock is acquired
+            .truncate(false)
+            .mode(0o644)
+            .open(path)
+        {
+            Ok(value) => value,
+            Err(e) => return Err(anyhow!("failed to open lock file {:?}: {}", path, e)),
+        };
+
+        if !flock(&file, libc::LOCK_EX | libc::LOCK_NB)? {
+            let holder: String = match holder(&mut file) {
+                Some(pid) => format!("the eri run with PID {}", pid),
+                None => "another eri run".to_owned(),
+            };
+            if !wait {
+                return Err(anyhow!(
+                    "{:?} is locked by {}, use --wait to wait for it",
+                    path,
+                    holder
+                ));
+            }
+            log::info!("Waiting for {} to release {:?}", holder, path);
+            flock(&file, libc::LOCK_EX)?;
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        log::debug!("Acquired the lock {:?}", path);
+        Ok(Lock {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // the PID is cleared so that it's not reported once the lock is released
+        let _ = self.file.set_len(0);
+        let _ = flock(&self.file, libc::LOCK_UN);
+        log::debug!("Released the lock {:?}", self.path);
+    }
+}
+
+/// Apply an operation to the lock of a file.
+/// Returns false if the lock is held by another process and the operation does not block.
+fn flock(file: &File, operation: libc::c_int) -> Result<bool> {
+    loop {
+        let ret_val: libc::c_int = unsafe { libc::flock(file.as_raw_fd(), operation) };
+        if ret_val == 0 {
+            return Ok(true);
+        }
+        let errno_val: i32 = errno::errno().into();
+        match errno_val {
+            libc::EWOULDBLOCK => return Ok(false),
+            libc::EINTR => continue,
+            _ => return Err(anyhow!("flock: {}", errno::errno())),
+        }
+    }
+}
+
+/// Get the PID written in a lock file by the run holding the lock.
+fn holder(file: &mut File) -> Option<u32> {
+    let mut contents: String = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    #[test]
+    fn acquire_held_lock() {
+        let dir: TestDir = TestDir::new("lock-held");
+        let path: PathBuf = dir.join(LOCK_FILE_NAME);
+        let lock: Lock = Lock::acquire(&path, false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        let e: String = Lock::acquire(&path, false).unwrap_err().to_string();
+        assert!(
+            e.contains(&format!("the eri run with PID {}", std::process::id())),
+            "{}",
+            e
+        );
+
+        drop(lock);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        assert!(Lock::acquire(&path, false).is_ok());
+    }
+}
@@ -1,16 +1,34 @@
 #[macro_use]
 extern crate anyhow;
 
+mod accounts;
+mod backup;
 mod config;
+mod crypt;
 mod data;
+mod exec;
+mod facts;
+mod files;
+mod frontmatter;
+mod helpers;
+mod inventory;
+mod lock;
+mod manifest;
 mod namespace;
+mod output;
+mod sensitive;
+mod state;
 mod template;
+#[cfg(test)]
+mod testing;
+mod vault;
 
 use chrono::Duration;
 use chrono::Local;
 
 use clap::App;
 use clap::Arg;
+use clap::ArgMatches;
 use clap::SubCommand;
 
 use colored::*;
@@ -20,11 +38,21 @@ use log::LevelFilter;
 
 use handlebars::Handlebars;
 
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
 /// The version of eri
 const ERI_VERSION: &str = "0.0.0";
 
 fn main() {
-    human_panic::setup_panic!();
+    // human-panic still names the panic hook info `PanicInfo`.
+    #[allow(deprecated)]
+    {
+        human_panic::setup_panic!();
+    }
     let mut app: App = App::new("eri")
         .version(ERI_VERSION)
         .author("Armand Cezar Mathe <me@cezarmathe.com>")
@@ -35,12 +63,126 @@ fn main() {
                 .multiple(true)
                 .help("Set the verbosity level of the messages outputed by eri. (-v for debug level, -vv for trace level)"),
         )
+        .arg(
+            Arg::with_name("facts-file")
+                .long("facts-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Load the facts about the host from a data file instead of gathering them."),
+        )
+        .arg(
+            Arg::with_name("root")
+                .long("root")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Render into an alternate root(e.g. the mount point of an image), using its users and groups."),
+        )
+        .arg(
+            Arg::with_name("wait")
+                .long("wait")
+                .help("Wait for another run of eri that changes files to finish, instead of failing."),
+        )
+        .arg(
+            Arg::with_name("no-wait")
+                .long("no-wait")
+                .conflicts_with("wait")
+                .help("Fail if another run of eri that changes files is in progress. This is the default."),
+        )
+        .arg(
+            Arg::with_name("show-secrets")
+                .long("show-secrets")
+                .help("Show sensitive values in the messages outputed by eri instead of redacting them. Only meant for local debugging."),
+        )
         .subcommand(
-            SubCommand::with_name("render").about("Render the templates specified by eri.conf."),
+            SubCommand::with_name("render")
+                .about("Render the templates specified by eri.conf.")
+                .arg(
+                    Arg::with_name("inventory")
+                        .long("inventory")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Render the namespaces for every host of an inventory, into <host> inside OUT or ARCHIVE."),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .takes_value(true)
+                        .value_name("OUT")
+                        .help("Render the files inside a directory instead of the root directory."),
+                )
+                .arg(
+                    Arg::with_name("archive")
+                        .long("archive")
+                        .takes_value(true)
+                        .value_name("ARCHIVE")
+                        .conflicts_with("out")
+                        .help("Write the rendered files into a tar archive(compressed if it ends with .gz), with their ownership and permissions."),
+                )
+                .arg(
+                    Arg::with_name("prune")
+                        .long("prune")
+                        .conflicts_with("archive")
+                        .help("Remove the files eri rendered before, but no longer renders."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Remove the files eri rendered before, but no longer renders, without rendering the others."),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Compare the files on the disk with the ones eri would render, exiting with an error if they drifted.")
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Report the drifted files as json."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("List the runs of eri whose backups are kept."),
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about("Restore the files changed by a run(the last one by default) as they were before it.")
+                .arg(Arg::with_name("run").value_name("RUN")),
         )
         .subcommand(
             SubCommand::with_name("gendata")
                 .about("Generate the data files requires by each namespace."),
+        )
+        .subcommand(
+            SubCommand::with_name("encrypt")
+                .about("Encrypt a value(read from stdin if missing) or a file, for use in the data.")
+                .arg(Arg::with_name("value").value_name("VALUE"))
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .conflicts_with("value")
+                        .help("Encrypt a file into FILE.age."),
+                )
+                .arg(recipient_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("decrypt")
+                .about("Decrypt a value(read from stdin if missing) or a file.")
+                .arg(Arg::with_name("value").value_name("VALUE"))
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .conflicts_with("value")
+                        .help("Decrypt a file and print it."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("edit")
+                .about("Edit an encrypted file, creating it if it does not exist.")
+                .arg(Arg::with_name("file").value_name("FILE").required(true))
+                .arg(recipient_arg()),
         );
     let matches = app.clone().get_matches();
 
@@ -53,7 +195,7 @@ fn main() {
             std::process::exit(0);
         }
     };
-    fern::Dispatch::new()
+    let logger: fern::Dispatch = fern::Dispatch::new()
         .format(|out, message, record| {
             let prefix: String = match record.level() {
                 Level::Error => "ERROR >".red().bold().to_string(),
@@ -62,38 +204,151 @@ fn main() {
                 Level::Debug => "DEBUG >".cyan().bold().to_string(),
                 Level::Trace => "TRACE >".purple().bold().to_string(),
             };
-            out.finish(format_args!("{} {}", prefix, message));
+            out.finish(format_args!(
+                "{} {}",
+                prefix,
+                sensitive::redact(&message.to_string())
+            ));
         })
         .level(log_level)
-        .level_for("users", LevelFilter::Info)
-        .chain(std::io::stdout())
-        .apply()
-        .unwrap();
+        .level_for("users", LevelFilter::Info);
+    // the json report of verify is the only output on stdout
+    let json_output: bool = matches
+        .subcommand_matches("verify")
+        .is_some_and(|value| value.is_present("json"));
+    if json_output {
+        logger.chain(std::io::stderr()).apply().unwrap();
+    } else {
+        logger.chain(std::io::stdout()).apply().unwrap();
+    }
+    if matches.is_present("show-secrets") {
+        sensitive::show();
+        log::warn!("Sensitive values are shown, do not share this output.");
+    }
 
-    let eri_config = match config::EriConfig::open() {
+    if let (name, Some(sub_matches)) = matches.subcommand() {
+        if name == "encrypt" || name == "decrypt" || name == "edit" {
+            if let Err(e) = run_crypt_command(name, sub_matches) {
+                log::error!("Failed to {}: {:#?}", name, e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
+    if let Some(root) = matches.value_of("root") {
+        if let Err(e) = accounts::set_root(Path::new(root)) {
+            log::error!("Failed to read the accounts of the root {}: {:#?}", root, e);
+            std::process::exit(1);
+        }
+    }
+
+    let mut eri_config = match config::EriConfig::open() {
         Ok(value) => value,
         Err(e) => {
             log::error!("Failed to open the eri configuration: {:#?}", e);
             std::process::exit(1);
         }
     };
+    if let Some(root) = matches.value_of("root") {
+        eri_config.export.root = Some(root.to_owned());
+    }
 
-    let namespaces: Vec<namespace::Namespace> = match eri_config.namespaces() {
-        Ok(value) => value,
-        Err(e) => {
-            log::error!("Failed to load the namespaces: {:#?}", e);
-            std::process::exit(1);
+    // runs that change files hold the lock until eri exits
+    let _lock: Option<lock::Lock> = match matches.subcommand_name() {
+        Some("render") | Some("gendata") | Some("prune") | Some("rollback") => {
+            match lock::Lock::acquire(Path::new(lock::LOCK_FILE_NAME), matches.is_present("wait")) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    log::error!("Failed to acquire the lock: {:#?}", e);
+                    std::process::exit(1);
+                }
+            }
         }
+        _ => None,
     };
 
+    if let (name, Some(sub_matches)) = matches.subcommand() {
+        if name == "history" || name == "rollback" {
+            if let Err(e) = run_backup_command(&eri_config, name, sub_matches) {
+                log::error!("Failed to {}: {:#?}", name, e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
+    let render_matches: Option<&ArgMatches> = matches.subcommand_matches("render");
+    let prune_matches: Option<&ArgMatches> = matches.subcommand_matches("prune");
+    let hosts: Vec<Option<inventory::Host>> =
+        match render_matches.and_then(|value| value.value_of("inventory")) {
+            Some(inventory_file) => match inventory::load(Path::new(inventory_file)) {
+                Ok(value) => value.into_iter().map(Some).collect(),
+                Err(e) => {
+                    log::error!("Failed to load the inventory: {:#?}", e);
+                    std::process::exit(1);
+                }
+            },
+            None => vec![None],
+        };
+    if let Some(out) = render_matches.and_then(|value| value.value_of("out")) {
+        eri_config.export.root = Some(out.to_owned());
+    }
+    let archive: Option<&str> = render_matches.and_then(|value| value.value_of("archive"));
+    if archive.is_some() {
+        // files are placed relative to the root of the archive
+        eri_config.export.root = Some(String::new());
+    }
+    let inventory: bool = hosts.first().is_some_and(Option::is_some);
+    if inventory && eri_config.export.root.is_none() {
+        log::error!("Rendering an inventory requires either --out or --archive");
+        std::process::exit(1);
+    }
+
+    if let Some(facts_file) = matches.value_of("facts-file") {
+        match facts::load(Path::new(facts_file)) {
+            Ok(value) => eri_config.set_facts(value),
+            Err(e) => {
+                log::error!("Failed to load the facts: {:#?}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if eri_config.facts && !inventory {
+        // the facts of the local host do not describe the hosts of an inventory
+        match facts::gather() {
+            Ok(value) => eri_config.set_facts(value),
+            Err(e) => {
+                log::error!("Failed to gather the facts: {:#?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     let mut handlebars = Handlebars::new();
 
-    if matches.subcommand_matches("render").is_some() {
+    if render_matches.is_some() || prune_matches.is_some() {
+        let mut output: Box<dyn output::Output> = match archive {
+            Some(path) => match output::Archive::create(Path::new(path)) {
+                Ok(value) => Box::new(value),
+                Err(e) => {
+                    log::error!("Failed to create the archive: {:#?}", e);
+                    std::process::exit(1);
+                }
+            },
+            None if prune_matches.is_some() => Box::new(output::Filesystem::prune_only(start_run(
+                &eri_config,
+                "prune",
+            ))),
+            None => Box::new(output::Filesystem::new(
+                render_matches.is_some_and(|value| value.is_present("prune")),
+                start_run(&eri_config, "render"),
+            )),
+        };
         let before = Local::now();
-        for namespace in namespaces {
-            if let Err(e) = namespace.render(&mut handlebars) {
-                log::error!("Failed to render namespace {:#?}: {:#?}", namespace, e);
-            }
+        render_namespaces(&eri_config, &hosts, &mut handlebars, output.as_mut());
+        if let Err(e) = output.finish() {
+            log::error!("Failed to write the rendered files: {:#?}", e);
+            std::process::exit(1);
         }
         let duration: Duration = Local::now() - before;
         if duration.num_seconds() > 0 {
@@ -107,17 +362,193 @@ fn main() {
                 duration.num_microseconds().unwrap() as f64 / 1000.0
             )
         }
+    } else if let Some(verify_matches) = matches.subcommand_matches("verify") {
+        let mut verify: output::Verify = output::Verify::default();
+        let rendered: bool = render_namespaces(&eri_config, &hosts, &mut handlebars, &mut verify);
+        verify.report(verify_matches.is_present("json"));
+        if !rendered || !verify.drifts.is_empty() {
+            std::process::exit(1);
+        }
     } else if matches.subcommand_matches("gendata").is_some() {
-        for namespace in namespaces {
-            if let Err(e) = namespace.gen_data_file(&mut handlebars) {
+        let mut backup: backup::Run = start_run(&eri_config, "gendata");
+        let mut generated: Vec<String> = Vec::new();
+        let namespaces: Vec<namespace::Namespace> = load_namespaces(&eri_config);
+        for namespace in load_instances(&namespaces, None) {
+            // all the instances of a namespace share its data file
+            if generated.contains(&namespace.name) {
+                continue;
+            }
+            generated.push(namespace.name.clone());
+            if let Err(e) = namespace.gen_data_file(&mut handlebars, &mut backup) {
                 log::error!(
-                    "Failed to generate the data file for the namespace {:#?}: {:#?}",
-                    namespace,
+                    "Failed to generate the data file for the namespace {}: {:#?}",
+                    namespace.name,
                     e
                 );
             }
         }
+        if let Err(e) = backup.finish() {
+            log::error!("Failed to finish the backups: {:#?}", e);
+            std::process::exit(1);
+        }
     } else {
         app.print_help().unwrap();
     }
 }
+
+/// Render the namespaces for each host into an output.
+/// Returns whether all the namespaces were rendered.
+fn render_namespaces(
+    eri_config: &config::EriConfig,
+    hosts: &[Option<inventory::Host>],
+    handlebars: &mut Handlebars,
+    output: &mut dyn output::Output,
+) -> bool {
+    let mut rendered: bool = true;
+    // the data sources are resolved once, for all the hosts
+    let namespaces: Vec<namespace::Namespace> = load_namespaces(eri_config);
+    for host in hosts {
+        if let Some(host) = host {
+            log::info!("Rendering host {}", host.name);
+        }
+        // the instances of a namespace must not render the same files
+        let mut files: BTreeMap<String, BTreeMap<PathBuf, String>> = BTreeMap::new();
+        for namespace in load_instances(&namespaces, host.as_ref()) {
+            let namespace_files: &mut BTreeMap<PathBuf, String> =
+                files.entry(namespace.name.clone()).or_default();
+            if let Err(e) = namespace.render(handlebars, output, namespace_files) {
+                log::error!("Failed to render namespace {}: {:#?}", namespace.name, e);
+                rendered = false;
+            }
+        }
+    }
+    rendered
+}
+
+/// Load the namespaces, exiting on failure.
+fn load_namespaces(eri_config: &config::EriConfig) -> Vec<namespace::Namespace<'_>> {
+    match eri_config.namespaces() {
+        Ok(value) => value,
+        Err(e) => {
+            log::error!("Failed to load the namespaces: {:#?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Get the instances of the namespaces, for a host of the inventory if there is one,
+/// exiting on failure.
+fn load_instances<'a>(
+    namespaces: &[namespace::Namespace<'a>],
+    host: Option<&inventory::Host>,
+) -> Vec<namespace::Namespace<'a>> {
+    match config::instances(namespaces, host) {
+        Ok(value) => value,
+        Err(e) => {
+            log::error!("Failed to load the namespaces: {:#?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Start a run of a command, exiting on failure.
+fn start_run(eri_config: &config::EriConfig, command: &str) -> backup::Run {
+    match backup::Run::start(&eri_config.backup, command) {
+        Ok(value) => value,
+        Err(e) => {
+            log::error!("Failed to start the backups: {:#?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run one of the subcommands that manage the backups of the files eri changed.
+fn run_backup_command(
+    eri_config: &config::EriConfig,
+    name: &str,
+    matches: &ArgMatches,
+) -> anyhow::Result<()> {
+    let runs: Vec<backup::Run> = backup::history(&eri_config.backup)?;
+    if name == "history" {
+        if runs.is_empty() {
+            log::info!("No backups are kept");
+        }
+        for run in &runs {
+            log::info!(
+                "{}  {}  {}, {} files changed",
+                run.id,
+                run.time.format("%Y-%m-%d %H:%M:%S"),
+                run.command,
+                run.entries.len()
+            );
+        }
+        return Ok(());
+    }
+
+    let run: &backup::Run = match matches.value_of("run") {
+        Some(id) => match runs.iter().find(|run| run.id == id) {
+            Some(value) => value,
+            None => return Err(anyhow!("no backups are kept for run {}", id)),
+        },
+        None => match runs.last() {
+            Some(value) => value,
+            None => return Err(anyhow!("no backups are kept")),
+        },
+    };
+    log::info!("Rolling back run {}, {}", run.id, run.command);
+    let mut rollback: backup::Run =
+        backup::Run::start(&eri_config.backup, &format!("rollback {}", run.id))?;
+    let result: anyhow::Result<()> = run.rollback(&mut rollback);
+    rollback.finish()?;
+    result
+}
+
+/// The argument used to set the recipients of encrypted data.
+fn recipient_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("recipient")
+        .short("r")
+        .long("recipient")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("RECIPIENT")
+        .help("Encrypt to an age recipient. By default, data is encrypted to the local identities.")
+}
+
+/// Run one of the subcommands that manage encrypted data.
+fn run_crypt_command(name: &str, matches: &ArgMatches) -> anyhow::Result<()> {
+    let recipients: Vec<String> = match matches.values_of("recipient") {
+        Some(values) => values.map(str::to_owned).collect(),
+        None => Vec::new(),
+    };
+    let read_value = || -> anyhow::Result<String> {
+        match matches.value_of("value") {
+            Some(value) => Ok(value.to_owned()),
+            None => {
+                let mut value: String = String::new();
+                std::io::stdin().read_to_string(&mut value)?;
+                Ok(value.trim_end_matches('\n').to_owned())
+            }
+        }
+    };
+
+    match name {
+        "encrypt" => match matches.value_of("file") {
+            Some(file) => {
+                let encrypted_file: String = format!("{}.{}", file, crypt::EXTENSION);
+                std::fs::write(
+                    &encrypted_file,
+                    crypt::encrypt(&std::fs::read(file)?, &recipients)?,
+                )?;
+                log::info!("{} was encrypted into {}", file, encrypted_file);
+            }
+            None => println!("{}", crypt::encrypt_value(&read_value()?, &recipients)?),
+        },
+        "decrypt" => match matches.value_of("file") {
+            Some(file) => std::io::stdout().write_all(&crypt::decrypt_file(Path::new(file))?)?,
+            None => println!("{}", crypt::decrypt_value(&read_value()?)?),
+        },
+        _ => crypt::edit_file(Path::new(matches.value_of("file").unwrap()), &recipients)?,
+    }
+    Ok(())
+}
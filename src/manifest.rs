@@ -0,0 +1,216 @@
+use crate::files;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+
+use sha2::Digest;
+use sha2::Sha256;
+
+/// The name of the file inside an export directory where the files eri rendered are kept.
+pub const MANIFEST_FILE_NAME: &str = ".eri-manifest.json";
+
+/// A file rendered by eri.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    /// The namespace that rendered the file(e.g. `vault` or `vault/instance`).
+    pub namespace: String,
+    /// The sha256 hash of the contents of the file.
+    pub sha256: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    /// Whether eri created the file, rather than replacing an existing one.
+    /// Only the files created by eri are removed once they are stale.
+    pub created: bool,
+}
+
+/// The files rendered by eri inside an export directory, by path relative to it.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    pub files: BTreeMap<String, ManifestEntry>,
+    /// The files the namespaces rendering into the export directory depend on besides their
+    /// templates(e.g. data files or files read by templates), by namespace.
+    /// Each file has the sha256 hash of its contents, if it exists.
+    pub dependencies: BTreeMap<String, BTreeMap<String, Option<String>>>,
+}
+
+impl Manifest {
+    /// Open the manifest of an export directory, which is empty if it does not exist.
+    pub fn open(dir: &Path) -> Result<Self> {
+        let path: PathBuf = dir.join(MANIFEST_FILE_NAME);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        let src: String = match std::fs::read_to_string(&path) {
+            Ok(value) => value,
+            Err(e) => return Err(anyhow!("failed to read manifest {:?}: {}", path, e)),
+        };
+        let value: Value = match serde_json::from_str(&src) {
+            Ok(value) => value,
+            Err(e) => return Err(anyhow!("failed to parse manifest {:?}: {}", path, e)),
+        };
+
+        let mut files: BTreeMap<String, ManifestEntry> = BTreeMap::new();
+        if let Some(entries) = value.get("files").and_then(Value::as_object) {
+            for (name, entry) in entries {
+                match entry_from_value(entry) {
+                    Some(value) => files.insert(name.clone(), value),
+                    None => return Err(anyhow!("invalid entry {} in manifest {:?}", name, path)),
+                };
+            }
+        }
+        let mut dependencies: BTreeMap<String, BTreeMap<String, Option<String>>> = BTreeMap::new();
+        if let Some(namespaces) = value.get("dependencies").and_then(Value::as_object) {
+            for (namespace, files) in namespaces {
+                match dependencies_from_value(files) {
+                    Some(value) => dependencies.insert(namespace.clone(), value),
+                    None => {
+                        return Err(anyhow!(
+                            "invalid dependencies of {} in manifest {:?}",
+                            namespace,
+                            path
+                        ))
+                    }
+                };
+            }
+        }
+        Ok(Manifest {
+            files,
+            dependencies,
+        })
+    }
+
+    /// Save the manifest of an export directory, readable only by the current user, since
+    /// the hashes of files holding secrets could be used to guess them.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let mut files: Map<String, Value> = Map::new();
+        for (name, entry) in &self.files {
+            files.insert(
+                name.clone(),
+                json!({
+                    "namespace": entry.namespace,
+                    "sha256": entry.sha256,
+                    "uid": entry.uid,
+                    "gid": entry.gid,
+                    "mode": format!("{:o}", entry.mode),
+                    "created": entry.created,
+                }),
+            );
+        }
+
+        let manifest: Value = json!({ "files": files, "dependencies": self.dependencies });
+        files::atomic_write(
+            &dir.join(MANIFEST_FILE_NAME),
+            serde_json::to_string_pretty(&manifest)?.as_bytes(),
+            0o600,
+            None,
+        )
+    }
+}
+
+/// Get the sha256 hash of some contents, hex encoded.
+pub fn sha256(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/// Get the hashes of the files a namespace depends on, by path.
+pub fn dependencies(paths: &[PathBuf]) -> Result<BTreeMap<String, Option<String>>> {
+    let mut dependencies: BTreeMap<String, Option<String>> = BTreeMap::new();
+    for path in paths {
+        let name: String = match path.to_str() {
+            Some(value) => value.to_owned(),
+            None => return Err(anyhow!("invalid file name: {:?}", path)),
+        };
+        let hash: Option<String> = if path.is_file() {
+            Some(sha256(&std::fs::read(path)?))
+        } else {
+            None
+        };
+        dependencies.insert(name, hash);
+    }
+    Ok(dependencies)
+}
+
+/// Read the dependencies of a namespace in a manifest.
+fn dependencies_from_value(value: &Value) -> Option<BTreeMap<String, Option<String>>> {
+    let mut dependencies: BTreeMap<String, Option<String>> = BTreeMap::new();
+    for (path, hash) in value.as_object()? {
+        let hash: Option<String> = match hash {
+            Value::Null => None,
+            value => Some(value.as_str()?.to_owned()),
+        };
+        dependencies.insert(path.clone(), hash);
+    }
+    Some(dependencies)
+}
+
+/// Read an entry of a manifest.
+fn entry_from_value(value: &Value) -> Option<ManifestEntry> {
+    Some(ManifestEntry {
+        namespace: value.get("namespace")?.as_str()?.to_owned(),
+        sha256: value.get("sha256")?.as_str()?.to_owned(),
+        uid: value.get("uid")?.as_u64()? as u32,
+        gid: value.get("gid")?.as_u64()? as u32,
+        mode: u32::from_str_radix(value.get("mode")?.as_str()?, 8).ok()?,
+        created: value.get("created")?.as_bool()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    #[test]
+    fn save_and_open() {
+        let dir: TestDir = TestDir::new("manifest-save");
+        let mut manifest: Manifest = Manifest::default();
+        manifest.files.insert(
+            "conf.d/redis.conf".to_owned(),
+            ManifestEntry {
+                namespace: "redis/cache".to_owned(),
+                sha256: sha256(b"port 6379\n"),
+                uid: 100,
+                gid: 101,
+                mode: 0o640,
+                created: true,
+            },
+        );
+        let mut dependencies: BTreeMap<String, Option<String>> = BTreeMap::new();
+        dependencies.insert("/srv/eri/redis/data.json".to_owned(), Some(sha256(b"{}")));
+        dependencies.insert("/srv/eri/redis/missing.json".to_owned(), None);
+        manifest
+            .dependencies
+            .insert("redis/cache".to_owned(), dependencies);
+        manifest.save(&dir).unwrap();
+
+        let saved: String = std::fs::read_to_string(dir.join(MANIFEST_FILE_NAME)).unwrap();
+        assert!(saved.contains("\"mode\": \"640\""), "{}", saved);
+        let opened: Manifest = Manifest::open(&dir).unwrap();
+        assert_eq!(opened.files, manifest.files);
+        assert_eq!(opened.dependencies, manifest.dependencies);
+    }
+
+    #[test]
+    fn open_invalid() {
+        let dir: TestDir = TestDir::new("manifest-invalid");
+        assert!(Manifest::open(&dir).unwrap().files.is_empty());
+
+        std::fs::write(
+            dir.join(MANIFEST_FILE_NAME),
+            "{\"files\": {\"a\": {\"mode\": \"9\"}}}",
+        )
+        .unwrap();
+        assert!(Manifest::open(&dir).is_err());
+        std::fs::write(dir.join(MANIFEST_FILE_NAME), "not json").unwrap();
+        assert!(Manifest::open(&dir).is_err());
+    }
+}
@@ -1,12 +1,33 @@
+use crate::backup::Run;
+use crate::config;
+use crate::config::EriConfig;
 use crate::config::ExportConfig;
+use crate::config::KindPatterns;
+use crate::config::NamespaceConfig;
+use crate::config::RESERVED_KEY;
+use crate::crypt;
 use crate::data;
+use crate::exec;
+use crate::files::Files;
+use crate::frontmatter::FrontMatter;
+use crate::helpers;
+use crate::inventory::Host;
+use crate::manifest::MANIFEST_FILE_NAME;
+use crate::output::Output;
+use crate::sensitive;
+use crate::state::State;
+use crate::state::STATE_FILE_NAME;
 use crate::template::*;
+use crate::vault;
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::fs::File;
 use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::Result;
 
@@ -14,6 +35,11 @@ use chrono::offset::Local;
 
 use handlebars::Handlebars;
 
+use ignore::overrides::Override;
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+
+use serde_json::json;
 use serde_json::Map;
 use serde_json::Value;
 
@@ -21,21 +47,51 @@ use uclicious::Parser;
 use uclicious::Priority;
 use uclicious::DEFAULT_DUPLICATE_STRATEGY;
 
+/// The name of the ignore files that can be placed inside a namespace directory.
+const IGNORE_FILE_NAME: &str = ".eriignore";
+
+/// Patterns of the files that are never templates.
+const DEFAULT_EXCLUDES: &[&str] = &[
+    "eri.conf",
+    "eri.conf.bk_*",
+    "README*",
+    IGNORE_FILE_NAME,
+    STATE_FILE_NAME,
+    MANIFEST_FILE_NAME,
+    ".*.eri-tmp",
+    ".git/",
+    ".gitignore",
+    "*~",
+    "*.swp",
+    "*.swo",
+    "#*#",
+];
+
 /// General representation of a namespace of templates.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Namespace<'a> {
     pub name: String,
+    pub host: Option<String>,
+    pub instance: Option<String>,
     pub base_path: PathBuf,
+    pub module_path: Option<PathBuf>,
     pub export_config: Cow<'a, ExportConfig>,
     pub data: Cow<'a, Map<String, Value>>,
+    pub config: NamespaceConfig,
+    pub kind_patterns: KindPatterns,
+    pub eri_config: &'a EriConfig,
+    pub data_files: Vec<PathBuf>,
+    pub files: Arc<Files>,
 }
 
 impl<'a> Namespace<'a> {
     /// Create a new namespace.
+    /// The vault client is created when the first namespace that reads secrets is created.
     pub fn new(
         name: &str,
-        export_config: &'a ExportConfig,
+        eri_config: &'a EriConfig,
         mut data: Cow<'a, Map<String, Value>>,
+        vault: &mut Option<vault::Client>,
     ) -> Result<Self> {
         let current_dir_path: PathBuf = match std::env::current_dir() {
             Ok(value) => value,
@@ -52,21 +108,7 @@ impl<'a> Namespace<'a> {
 
         let additional_eri_conf: PathBuf = base_path.join("eri.conf");
         if additional_eri_conf.exists() && additional_eri_conf.is_file() {
-            let eri_config_string: String = std::fs::read_to_string(additional_eri_conf)?;
-            let mut parser: Parser = Parser::default();
-            parser.add_chunk_full(
-                eri_config_string,
-                Priority::default(),
-                DEFAULT_DUPLICATE_STRATEGY,
-            )?;
-            let mut new_values: Map<String, Value> = Map::new();
-            for item in parser.get_object()?.iter() {
-                let item_key = item.key().unwrap();
-                match data::object_ref_to_value(item) {
-                    Ok(value) => new_values.insert(item_key, value),
-                    Err(e) => return Err(e),
-                };
-            }
+            let mut new_values: Map<String, Value> = read_conf(&additional_eri_conf, name)?;
             if !new_values.is_empty() {
                 let mut namespace_data: Cow<Map<String, Value>> = match data.to_mut().get(name) {
                     Some(value) => {
@@ -84,41 +126,298 @@ impl<'a> Namespace<'a> {
             }
         }
 
+        let module_path: Option<PathBuf> = module(&current_dir_path, name, &mut data)?;
+
+        let config: NamespaceConfig = NamespaceConfig::extract(&mut data, name)?;
+        let kind_patterns: KindPatterns = config.kind_patterns()?;
+
+        let mut data_files: Vec<PathBuf> = Vec::new();
+        for pattern in &config.data_files {
+            for path in data::glob_paths(&base_path, pattern)? {
+                log::debug!("Loading data file {:?} for namespace {}", path, name);
+                let value: Value = data::load_file(&path)?;
+                if !value.is_object() {
+                    return Err(anyhow!("data file {:?} does not contain an object", path));
+                }
+                merge_data(&mut data, name, value);
+                data_files.push(path);
+            }
+        }
+
+        if !config.secrets.is_empty() {
+            if vault.is_none() {
+                *vault = Some(vault::Client::new(&eri_config.vault)?);
+            }
+            let client: &vault::Client = vault.as_ref().unwrap();
+            for (key, source) in &config.secrets {
+                let mut value: Map<String, Value> = Map::new();
+                value.insert(key.clone(), client.read(source)?);
+                let value: Value = Value::Object(value);
+                data::mark_verbatim(&value);
+                merge_data(&mut data, name, value);
+            }
+        }
+
+        for (key, source) in &config.exec {
+            let mut value: Map<String, Value> = Map::new();
+            value.insert(key.clone(), exec::run(source, &base_path)?);
+            let value: Value = Value::Object(value);
+            data::mark_verbatim(&value);
+            merge_data(&mut data, name, value);
+        }
+
+        if data.get(name).is_some_and(crypt::contains_encrypted) {
+            if let Some(namespace_data) = data.to_mut().get_mut(name) {
+                crypt::decrypt_all(namespace_data)?;
+            }
+        }
+        if let Some(namespace_data) = data.get(name) {
+            sensitive::mark_keys(namespace_data, &config.sensitive)?;
+        }
+
+        // only the data of the namespace itself is available, until other namespaces are
+        // imported, unless the namespace does not declare its imports
+        if config.imports.is_some() {
+            let mut namespace_data: Map<String, Value> = Map::new();
+            for key in &[name, RESERVED_KEY] {
+                if let Some(value) = data.get(*key) {
+                    namespace_data.insert((*key).to_owned(), value.clone());
+                }
+            }
+            data = Cow::Owned(namespace_data);
+        } else {
+            decrypt_declared(&mut data, name)?;
+        }
+
+        let mut allow_dirs: Vec<String> = eri_config.allow_dirs.clone();
+        if let Some(value) = module_path.as_ref().and_then(|path| path.to_str()) {
+            allow_dirs.push(value.to_owned());
+        }
+        let files: Files = Files::new(base_path.clone(), &allow_dirs)?;
+
+        let mut export_config: Cow<ExportConfig> = Cow::Borrowed(&eri_config.export);
+        if let Some(export) = &config.export {
+            if export.dir.is_some() {
+                export_config.to_mut().dir = export.dir.clone();
+            }
+            if export.user.is_some() {
+                export_config.to_mut().user = export.user.clone();
+            }
+            if export.group.is_some() {
+                export_config.to_mut().group = export.group.clone();
+            }
+            if export.permissions.is_some() {
+                export_config.to_mut().permissions = export.permissions;
+            }
+        }
+
         Ok(Namespace {
             name: name.to_owned(),
+            host: None,
+            instance: None,
             base_path,
-            export_config: Cow::Borrowed(export_config),
+            module_path,
+            export_config,
             data,
+            config,
+            kind_patterns,
+            eri_config,
+            data_files,
+            files: Arc::new(files),
         })
     }
 
-    /// Get the templates in this namespace.
-    pub fn templates(&self) -> Result<Vec<Template>> {
-        let mut vec: Vec<Template> = Vec::new();
+    /// Make the data of another namespace available to the templates of this namespace.
+    pub fn import(&mut self, other: &Namespace) {
+        if let Some(value) = other.data.get(&other.name) {
+            self.data.to_mut().insert(other.name.clone(), value.clone());
+        }
+    }
 
-        for file in std::fs::read_dir(&self.base_path)? {
-            let file = file?;
-            let file_path: PathBuf = file.path();
-            if file_path.ends_with("eri.conf") {
-                continue;
+    /// Resolve the references between the values of the namespace data(e.g. `${vault.data_dir}`).
+    pub fn interpolate(&mut self) -> Result<()> {
+        match data::interpolate(self.data.to_mut(), &self.name) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(anyhow!(
+                "failed to interpolate namespace {}: {}",
+                self.name,
+                e
+            )),
+        }
+    }
+
+    /// Get the instances of this namespace, each one with its instance data merged over the
+    /// namespace data.
+    /// A namespace without instances is its only instance.
+    pub fn instances(&self) -> Result<Vec<Namespace<'a>>> {
+        if self.config.instances.is_empty() {
+            return Ok(vec![self.clone()]);
+        }
+        let mut instances: Vec<Namespace<'a>> = Vec::new();
+        for (instance, value) in &self.config.instances {
+            let mut value: Value = value.clone();
+            if let Err(e) = self.decrypt_layer(&mut value) {
+                return Err(anyhow!(
+                    "failed to load the data of instance {} of namespace {}: {}",
+                    instance,
+                    self.name,
+                    e
+                ));
             }
-            let file_name: String = if let Some(os_str) = file_path.file_name() {
-                if let Some(value) = os_str.to_str() {
-                    value.to_owned()
-                } else {
-                    return Err(anyhow!("failed to get convert the file name into a string"));
-                }
-            } else {
+            let mut namespace: Namespace<'a> = self.clone();
+            let namespace_data: &mut Value = namespace
+                .data
+                .to_mut()
+                .entry(self.name.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            data::merge(namespace_data, value);
+            namespace.instance = Some(instance.clone());
+            instances.push(namespace);
+        }
+        Ok(instances)
+    }
+
+    /// Decrypt the encrypted values of data layered over the namespace data(e.g. the data of
+    /// an instance), and mark its sensitive values.
+    fn decrypt_layer(&self, value: &mut Value) -> Result<()> {
+        if crypt::contains_encrypted(value) {
+            crypt::decrypt_all(value)?;
+        }
+        sensitive::mark_keys(value, &self.config.sensitive)
+    }
+
+    /// Set the data provided by eri about the namespace: its own data under `eri.ns`, so
+    /// that templates shared through modules do not depend on the name of the namespace,
+    /// and the name of its instance under `eri.instance`.
+    pub fn set_reserved_data(&mut self) {
+        let value: Value = match self.data.get(&self.name) {
+            Some(value) => value.clone(),
+            None => Value::Object(Map::new()),
+        };
+        let instance: Option<String> = self.instance.clone();
+        let reserved: &mut Map<String, Value> = self.reserved_data();
+        reserved.insert("ns".to_owned(), value);
+        if let Some(instance) = instance {
+            reserved.insert("instance".to_owned(), Value::String(instance));
+        }
+    }
+
+    /// Layer the data of a host over the namespace data, and make the host available under
+    /// `eri.host`.
+    /// Files are exported inside the directory of the host, if an export root is set, and
+    /// the host generates its own values.
+    pub fn set_host(&mut self, host: &Host) -> Result<()> {
+        self.host = Some(host.name.clone());
+        if let Some(value) = host.vars.get(&self.name) {
+            let mut value: Value = value.clone();
+            if let Err(e) = self.decrypt_layer(&mut value) {
                 return Err(anyhow!(
-                    "failed to get the file name of the template at {:?}",
-                    file.path()
+                    "failed to load the data of host {} for namespace {}: {}",
+                    host.name,
+                    self.name,
+                    e
                 ));
+            }
+            let namespace_data: &mut Value = self
+                .data
+                .to_mut()
+                .entry(self.name.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            data::merge(namespace_data, value);
+        }
+        self.reserved_data().insert(
+            "host".to_owned(),
+            json!({ "name": host.name, "groups": host.groups }),
+        );
+        if let Some(root) = &self.export_config.root {
+            let host_root: PathBuf = PathBuf::from(root).join(&host.name);
+            self.export_config.to_mut().root = host_root.to_str().map(str::to_owned);
+        }
+        Ok(())
+    }
+
+    /// Get the data provided by eri.
+    fn reserved_data(&mut self) -> &mut Map<String, Value> {
+        let reserved: &mut Value = self
+            .data
+            .to_mut()
+            .entry(RESERVED_KEY)
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !reserved.is_object() {
+            *reserved = Value::Object(Map::new());
+        }
+        match reserved {
+            Value::Object(value) => value,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Render the export directory, which can use the namespace data and the name of the
+    /// instance under `instance`(e.g. `/etc/redis/{{instance}}`).
+    pub fn render_export_dir(&mut self) -> Result<()> {
+        let dir: String = match &self.export_config.dir {
+            Some(value) if value.contains("{{") => value.clone(),
+            _ => return Ok(()),
+        };
+        let mut handlebars: Handlebars = Handlebars::new();
+        handlebars.register_escape_fn(handlebars::no_escape);
+        handlebars.set_strict_mode(true);
+        let mut context: Cow<Map<String, Value>> = Cow::Borrowed(&*self.data);
+        if let Some(instance) = &self.instance {
+            // a namespace named instance takes precedence
+            if !context.contains_key("instance") {
+                context
+                    .to_mut()
+                    .insert("instance".to_owned(), Value::String(instance.clone()));
+            }
+        }
+        match handlebars.render_template(&dir, &context) {
+            Ok(value) => self.export_config.to_mut().dir = Some(value),
+            Err(e) => {
+                return Err(anyhow!(
+                    "failed to render the export directory of namespace {}: {}",
+                    self.name,
+                    e
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the templates in this namespace.
+    ///
+    /// Files matched by an `.eriignore` file and files filtered out by the `include` and
+    /// `exclude` patterns of the eri configuration are skipped.
+    /// Subdirectories are only searched if the namespace is `recursive`.
+    /// Templates of the extended module are included, unless the namespace directory has
+    /// a file with the same name.
+    pub fn templates(&self) -> Result<Vec<Template<'_>>> {
+        let mut vec: Vec<Template> = Vec::new();
+
+        let mut files: BTreeMap<String, PathBuf> = BTreeMap::new();
+        if let Some(module_path) = &self.module_path {
+            files.append(&mut self.template_files(module_path)?);
+        }
+        files.append(&mut self.template_files(&self.base_path)?);
+
+        for (file_name, file_path) in files {
+            let mut front_matter: FrontMatter = match self.config.templates.get(&file_name) {
+                Some(value) => value.clone(),
+                None => FrontMatter::default(),
             };
+            if front_matter.kind.is_none() {
+                front_matter.kind = match self.kind_patterns.kind_of(&file_name) {
+                    Some(Kind::Copy) => Some("copy".to_owned()),
+                    Some(Kind::Template) => Some("template".to_owned()),
+                    None => None,
+                };
+            }
             let _template: Template = Template::new(
                 format!("{}/{}", &self.name, file_name),
-                file.path(),
-                &self.data,
+                file_path,
+                Cow::Borrowed(&self.data),
                 std::borrow::Cow::Borrowed(&self.export_config),
+                front_matter,
             )?;
             vec.push(_template);
         }
@@ -126,20 +425,85 @@ impl<'a> Namespace<'a> {
         Ok(vec)
     }
 
-    /// Generate a data file required by this namespace.
-    pub fn gen_data_file(&self, handlebars: &mut Handlebars) -> Result<()> {
+    /// Get the template files inside a directory, by their name relative to the directory.
+    fn template_files(&self, dir: &Path) -> Result<BTreeMap<String, PathBuf>> {
+        let mut files: BTreeMap<String, PathBuf> = BTreeMap::new();
+
+        let walk = WalkBuilder::new(dir)
+            .standard_filters(false)
+            .hidden(false)
+            .add_custom_ignore_filename(IGNORE_FILE_NAME)
+            .overrides(overrides(
+                dir,
+                &self.eri_config.include,
+                &self.eri_config.exclude,
+            )?)
+            .max_depth(if self.config.recursive { None } else { Some(1) })
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+        for file in walk {
+            let file = file?;
+            let file_path: PathBuf = file.path().to_path_buf();
+            if !file_path.is_file() || self.data_files.contains(&file_path) {
+                continue;
+            }
+            let file_name: String = match file_path.strip_prefix(dir)?.to_str() {
+                Some(value) => value.to_owned(),
+                None => {
+                    return Err(anyhow!(
+                        "failed to convert the file name of the template at {:?} into a string",
+                        file_path
+                    ))
+                }
+            };
+            files.insert(file_name, file_path);
+        }
+
+        Ok(files)
+    }
+
+    /// Get the templates in this namespace, expanded into the templates that are rendered.
+    pub fn targets(&self) -> Result<Vec<Template<'_>>> {
+        let mut vec: Vec<Template> = Vec::new();
+        for template in self.templates()? {
+            vec.append(&mut template.expand()?);
+        }
+        Ok(vec)
+    }
+
+    /// Generate a data file required by this namespace, backing up the existing one.
+    pub fn gen_data_file(&self, handlebars: &mut Handlebars, backup: &mut Run) -> Result<()> {
         let templates: Vec<Template> = self.templates()?;
         for template in &templates {
             template.register(handlebars)?;
         }
 
+        // without declared imports, the data of all the namespaces is available
+        let imports: Vec<String> = match &self.config.imports {
+            Some(value) => value.clone(),
+            None => self
+                .data
+                .keys()
+                .filter(|key| *key != &self.name && key.as_str() != RESERVED_KEY)
+                .cloned()
+                .collect(),
+        };
         let params: BTreeSet<String> = {
             let mut params: BTreeSet<String> = BTreeSet::new();
             for template in templates {
-                for param in template.parameter_list(handlebars)? {
+                for param in template.parameter_list(handlebars, &imports)? {
                     let mut param_parts: Vec<&str> = param.split('.').collect();
-                    param_parts.remove(0);
-                    params.insert(param_parts.join("."));
+                    let namespace: &str = param_parts.remove(0);
+                    if namespace == self.name {
+                        params.insert(param_parts.join("."));
+                    } else if data::lookup(&self.data, &param).is_none() {
+                        log::warn!(
+                            "Template {} uses {}, which namespace {} does not define",
+                            template.name,
+                            param,
+                            namespace
+                        );
+                    }
                 }
             }
             params
@@ -149,22 +513,17 @@ impl<'a> Namespace<'a> {
         }
 
         let data_file_path: PathBuf = self.base_path.join("eri.conf");
-        if data_file_path.exists() && data_file_path.is_file() {
-            let data_file_backup_path: PathBuf = self
-                .base_path
-                .join(format!("eri.conf.bk_{:?}", Local::now()));
+        let exists: bool = data_file_path.is_file();
+        if let Err(e) = backup.snapshot(&data_file_path) {
+            return Err(anyhow!("Failed to make a backup, not proceeding: {:#?}", e));
+        }
+        if exists {
             log::info!(
-                "Data file for namespace {} already exists at {:?}, making a backup at {:?}",
+                "Data file for namespace {} already exists at {:?}, backed it up in run {}",
                 self.name,
                 data_file_path,
-                data_file_backup_path
+                backup.id
             );
-            match std::fs::copy(&data_file_path, data_file_backup_path) {
-                Ok(_) => log::debug!("Backup successful"),
-                Err(e) => {
-                    return Err(anyhow!("Failed to make a backup, not proceeding: {:#?}", e));
-                }
-            }
         }
 
         let mut file = match File::create(&data_file_path) {
@@ -196,16 +555,571 @@ impl<'a> Namespace<'a> {
         Ok(())
     }
 
-    /// Render all templates inside the namespace.
-    pub fn render(&self, handlebars: &mut Handlebars) -> Result<()> {
-        log::info!("Rendering namespace {}", self.name);
-        let templates: Vec<Template> = self.templates()?;
+    /// Get the name of the namespace, followed by the name of its instance if it has one
+    /// (e.g. `vault/instance`).
+    pub fn id(&self) -> String {
+        match &self.instance {
+            Some(instance) => format!("{}/{}", self.name, instance),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Render all templates inside the namespace, writing them to an output.
+    /// The files already rendered by the other instances of the namespace, by the template
+    /// that rendered them, are rejected.
+    pub fn render(
+        &self,
+        handlebars: &mut Handlebars,
+        output: &mut dyn Output,
+        rendered: &mut BTreeMap<PathBuf, String>,
+    ) -> Result<()> {
+        match &self.instance {
+            Some(instance) => {
+                log::info!("Rendering namespace {}, instance {}", self.name, instance)
+            }
+            None => log::info!("Rendering namespace {}", self.name),
+        }
+        let state: Arc<State> = Arc::new(State::new(
+            self.base_path.join(STATE_FILE_NAME),
+            self.host.clone(),
+            self.instance.clone(),
+            !output.writes(),
+        ));
+        helpers::register(handlebars, state.clone(), self.files.clone());
+        let templates: Vec<Template> = self.targets()?;
         for template in &templates {
             template.register(handlebars)?;
         }
+        let id: String = self.id();
         for template in &templates {
-            template.render(handlebars)?;
+            if let Some(file) = template.render(handlebars)? {
+                let template_name: String = match &self.instance {
+                    Some(instance) => format!("{} of instance {}", template.name, instance),
+                    None => template.name.clone(),
+                };
+                let path: PathBuf = file.path()?;
+                if let Some(other) = rendered.insert(path.clone(), template_name.clone()) {
+                    return Err(anyhow!(
+                        "templates {} and {} both render {:?}",
+                        other,
+                        template_name,
+                        path
+                    ));
+                }
+                output.write(&id, &file)?;
+            }
         }
+        let missing: Vec<String> = state.missing();
+        if !missing.is_empty() {
+            output.missing_values(&id, state.path(), &missing)?;
+        }
+        output.complete(
+            &id,
+            Path::new(self.export_config.dir.as_ref().unwrap()),
+            self.export_config.root.as_ref().map(Path::new),
+            &self.dependencies(),
+        )?;
         Ok(())
     }
+
+    /// Get the files, besides templates, that the rendered templates depend on: the data
+    /// files and the files accessed by templates so far.
+    pub fn dependencies(&self) -> Vec<PathBuf> {
+        let mut dependencies: Vec<PathBuf> = self.data_files.clone();
+        for path in self.files.accessed() {
+            if !dependencies.contains(&path) {
+                dependencies.push(path);
+            }
+        }
+        dependencies
+    }
+}
+
+/// Get the overrides that select the template files of a directory.
+fn overrides(dir: &Path, include: &[String], exclude: &[String]) -> Result<Override> {
+    let mut builder: OverrideBuilder = OverrideBuilder::new(dir);
+    for pattern in include {
+        builder.add(pattern)?;
+    }
+    for pattern in exclude {
+        builder.add(&format!("!{}", pattern))?;
+    }
+    for pattern in DEFAULT_EXCLUDES {
+        builder.add(&format!("!{}", pattern))?;
+    }
+    Ok(builder.build()?)
+}
+
+/// Decrypt the data declared by the other namespaces, which is available to a namespace that
+/// does not declare its imports, and mark its sensitive values.
+fn decrypt_declared(data: &mut Cow<Map<String, Value>>, name: &str) -> Result<()> {
+    let encrypted: Vec<String> = data
+        .iter()
+        .filter(|(key, value)| {
+            *key != name && *key != RESERVED_KEY && crypt::contains_encrypted(value)
+        })
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in encrypted {
+        if let Some(value) = data.to_mut().get_mut(&key) {
+            if let Err(e) = crypt::decrypt_all(value) {
+                return Err(anyhow!(
+                    "failed to decrypt the data of namespace {}: {}",
+                    key,
+                    e
+                ));
+            }
+        }
+    }
+    for (key, value) in data.iter() {
+        if key != name && key != RESERVED_KEY {
+            sensitive::mark_keys(value, &declared_sensitive(value))?;
+        }
+    }
+    Ok(())
+}
+
+/// Get the patterns of the sensitive keys declared in the `eri` block of the data of a
+/// namespace.
+fn declared_sensitive(value: &Value) -> Vec<String> {
+    match value
+        .get(RESERVED_KEY)
+        .and_then(|config| config.get("sensitive"))
+    {
+        Some(Value::Array(patterns)) => patterns
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_owned)
+            .collect(),
+        Some(Value::String(pattern)) => vec![pattern.clone()],
+        _ => Vec::new(),
+    }
+}
+
+/// Read the data of a namespace configuration file.
+fn read_conf(path: &Path, name: &str) -> Result<Map<String, Value>> {
+    let eri_config_string: String = std::fs::read_to_string(path)?;
+    let mut parser: Parser = Parser::default();
+    for (variable, value) in config::ucl_variables(Some(name))? {
+        parser.register_variable(variable, value);
+    }
+    parser.set_filevars(path, true)?;
+    parser.add_chunk_full(
+        config::resolve_includes(&eri_config_string, path.parent().unwrap()),
+        Priority::default(),
+        DEFAULT_DUPLICATE_STRATEGY,
+    )?;
+    let mut values: Map<String, Value> = Map::new();
+    for item in parser.get_object()?.iter() {
+        let item_key = item.key().unwrap();
+        match data::object_ref_to_value(item) {
+            Ok(value) => values.insert(item_key, value),
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(values)
+}
+
+/// Get the module directory a namespace extends, relative to a directory, and merge the
+/// data of the module under the namespace data.
+/// The module directory is declared by `extends` in the `eri` block of the namespace data,
+/// and its templates are inherited as well.
+fn module(dir: &Path, name: &str, data: &mut Cow<Map<String, Value>>) -> Result<Option<PathBuf>> {
+    let module_path: PathBuf = match data
+        .get(name)
+        .and_then(|value| value.get(RESERVED_KEY))
+        .and_then(|value| value.get("extends"))
+    {
+        Some(Value::String(module)) => {
+            let module_path: PathBuf = dir.join(module);
+            if !module_path.is_dir() {
+                return Err(anyhow!(
+                    "namespace {} extends {}, which is not a directory",
+                    name,
+                    module
+                ));
+            }
+            module_path
+        }
+        Some(_) => {
+            return Err(anyhow!(
+                "namespace {} should extend the path of a module directory",
+                name
+            ))
+        }
+        None => return Ok(None),
+    };
+
+    let module_eri_conf: PathBuf = module_path.join("eri.conf");
+    if module_eri_conf.is_file() {
+        // the data of the module are defaults, overridden by the namespace data
+        let mut value: Value = Value::Object(read_conf(&module_eri_conf, name)?);
+        if let Some(namespace_data) = data.get(name) {
+            data::merge(&mut value, namespace_data.clone());
+        }
+        data.to_mut().insert(name.to_owned(), value);
+    }
+    Ok(Some(module_path))
+}
+
+/// Merge a value into the data of a namespace.
+fn merge_data(data: &mut Cow<Map<String, Value>>, name: &str, value: Value) {
+    let namespace_data: &mut Value = data
+        .to_mut()
+        .entry(name)
+        .or_insert_with(|| Value::Object(Map::new()));
+    data::merge(namespace_data, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::output::RenderedFile;
+    use crate::testing;
+    use crate::testing::TestDir;
+
+    /// An output keeping the paths of the rendered files.
+    #[derive(Default)]
+    struct Paths(Vec<PathBuf>);
+
+    impl Output for Paths {
+        fn write(&mut self, _namespace: &str, file: &RenderedFile) -> Result<()> {
+            self.0.push(file.path()?);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// An output keeping the contents of the rendered files, which changes files so that
+    /// generated values are saved.
+    #[derive(Default)]
+    struct Contents(Vec<String>);
+
+    impl Output for Contents {
+        fn write(&mut self, _namespace: &str, file: &RenderedFile) -> Result<()> {
+            self.0.push(String::from_utf8(file.contents.clone())?);
+            Ok(())
+        }
+
+        fn writes(&self) -> bool {
+            true
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Get an eri configuration without namespaces.
+    fn eri_config() -> EriConfig {
+        let mut builder = EriConfig::builder().unwrap();
+        builder
+            .add_chunk_full(
+                "namespace {}",
+                Priority::default(),
+                DEFAULT_DUPLICATE_STRATEGY,
+            )
+            .unwrap();
+        builder.build().unwrap()
+    }
+
+    /// Get a namespace named redis, with a directory holding a template and an export directory.
+    fn redis<'a>(
+        eri_config: &'a EriConfig,
+        base_path: &Path,
+        value: Value,
+        dir: &str,
+    ) -> Namespace<'a> {
+        std::fs::write(base_path.join("redis.conf"), "port {{redis.port}}\n").unwrap();
+
+        let mut data: Cow<Map<String, Value>> =
+            Cow::Owned(json!({ "redis": value }).as_object().unwrap().clone());
+        let config: NamespaceConfig = NamespaceConfig::extract(&mut data, "redis").unwrap();
+        Namespace {
+            name: "redis".to_owned(),
+            host: None,
+            instance: None,
+            base_path: base_path.to_path_buf(),
+            module_path: None,
+            export_config: Cow::Owned(ExportConfig {
+                dir: Some(dir.to_owned()),
+                user: None,
+                group: None,
+                permissions: None,
+                root: None,
+            }),
+            data,
+            kind_patterns: config.kind_patterns().unwrap(),
+            config,
+            eri_config,
+            data_files: Vec::new(),
+            files: Arc::new(Files::new(base_path.to_path_buf(), &[]).unwrap()),
+        }
+    }
+
+    /// Render all the instances of a namespace, returning the paths of the rendered files.
+    fn render_instances(namespace: &Namespace) -> Result<Vec<PathBuf>> {
+        let mut handlebars: Handlebars = Handlebars::new();
+        let mut output: Paths = Paths::default();
+        let mut rendered: BTreeMap<PathBuf, String> = BTreeMap::new();
+        for mut instance in namespace.instances()? {
+            instance.set_reserved_data();
+            instance.render_export_dir()?;
+            instance.render(&mut handlebars, &mut output, &mut rendered)?;
+        }
+        Ok(output.0)
+    }
+
+    #[test]
+    fn instances_export_dirs() {
+        let eri_config: EriConfig = eri_config();
+        let value: Value = json!({
+            "port": 6379,
+            "eri": {"instances": {"cache": {"port": 6380}, "queue": {}}},
+        });
+        let base_path: TestDir = TestDir::new("namespace-instances");
+        let namespace: Namespace = redis(&eri_config, &base_path, value, "/etc/redis/{{instance}}");
+        let instances: Vec<Namespace> = namespace.instances().unwrap();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].data["redis"]["port"], 6380);
+        assert_eq!(instances[1].data["redis"]["port"], 6379);
+        assert_eq!(
+            render_instances(&namespace).unwrap(),
+            [
+                PathBuf::from("/etc/redis/cache/redis.conf"),
+                PathBuf::from("/etc/redis/queue/redis.conf"),
+            ]
+        );
+    }
+
+    #[test]
+    fn instances_decrypt_data() {
+        testing::age_identity();
+        let eri_config: EriConfig = eri_config();
+        let password: String = crypt::encrypt_value("cache-pass-9d3b7e", &[]).unwrap();
+        let value: Value = json!({
+            "eri": {
+                "instances": {"cache": {"auth": {"password": password, "token": "cache-token-9d3b7e"}}},
+            },
+        });
+        let base_path: TestDir = TestDir::new("namespace-instances-decrypt");
+        let namespace: Namespace = redis(&eri_config, &base_path, value, "/etc/redis");
+        let instances: Vec<Namespace> = namespace.instances().unwrap();
+        assert_eq!(
+            instances[0].data["redis"]["auth"]["password"],
+            "cache-pass-9d3b7e"
+        );
+        assert_eq!(
+            sensitive::redact("cache-pass-9d3b7e cache-token-9d3b7e"),
+            "<redacted> <redacted>"
+        );
+    }
+
+    #[test]
+    fn hosts_generate_own_values() {
+        let eri_config: EriConfig = eri_config();
+        let base_path: TestDir = TestDir::new("namespace-hosts");
+        let namespace: Namespace = redis(&eri_config, &base_path, json!({}), "/etc/redis");
+        std::fs::write(
+            base_path.join("redis.conf"),
+            "requirepass {{genPassword \"password\"}}\n# node {{uuid \"node_id\"}}\n",
+        )
+        .unwrap();
+
+        let mut handlebars: Handlebars = Handlebars::new();
+        let mut output: Contents = Contents::default();
+        for name in &["cache1", "cache2", "cache1"] {
+            let mut host_namespace: Namespace = namespace.clone();
+            host_namespace
+                .set_host(&Host {
+                    name: (*name).to_owned(),
+                    groups: Vec::new(),
+                    vars: Map::new(),
+                })
+                .unwrap();
+            host_namespace
+                .render(&mut handlebars, &mut output, &mut BTreeMap::new())
+                .unwrap();
+        }
+        let lines: Vec<Vec<&str>> = output.0.iter().map(|file| file.lines().collect()).collect();
+        assert_ne!(lines[0][0], lines[1][0]);
+        assert_ne!(lines[0][1], lines[1][1]);
+        // the values of a host are kept across renders
+        assert_eq!(lines[0], lines[2]);
+    }
+
+    #[test]
+    fn hosts_decrypt_data() {
+        testing::age_identity();
+        let eri_config: EriConfig = eri_config();
+        let password: String = crypt::encrypt_value("host-pass-0e6a2c", &[]).unwrap();
+        let base_path: TestDir = TestDir::new("namespace-hosts-decrypt");
+        let mut namespace: Namespace = redis(&eri_config, &base_path, json!({}), "/etc/redis");
+        namespace
+            .set_host(&Host {
+                name: "cache1".to_owned(),
+                groups: Vec::new(),
+                vars: json!({"redis": {"password": password, "api_token": "host-token-0e6a2c"}})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            })
+            .unwrap();
+        assert_eq!(namespace.data["redis"]["password"], "host-pass-0e6a2c");
+        assert_eq!(
+            sensitive::redact("host-pass-0e6a2c host-token-0e6a2c"),
+            "<redacted> <redacted>"
+        );
+    }
+
+    #[test]
+    fn instances_render_same_files() {
+        let eri_config: EriConfig = eri_config();
+        let value: Value = json!({"eri": {"instances": {"cache": {}, "queue": {}}}});
+        let base_path: TestDir = TestDir::new("namespace-same-files");
+        let namespace: Namespace = redis(&eri_config, &base_path, value, "/etc/redis");
+        let e: String = render_instances(&namespace).unwrap_err().to_string();
+        assert!(e.contains("both render"), "{}", e);
+    }
+
+    #[test]
+    fn templates_include_dotfiles() {
+        let eri_config: EriConfig = eri_config();
+        let value: Value = json!({"port": 6379, "eri": {"recursive": true}});
+        let base_path: TestDir = TestDir::new("namespace-dotfiles");
+        let namespace: Namespace = redis(&eri_config, &base_path, value, "/etc/redis");
+        std::fs::write(
+            namespace.base_path.join(".htaccess"),
+            "Require all denied\n",
+        )
+        .unwrap();
+        std::fs::write(
+            namespace.base_path.join(".redis.conf.eri-tmp"),
+            "port 6379\n",
+        )
+        .unwrap();
+        std::fs::create_dir_all(namespace.base_path.join(".git")).unwrap();
+        std::fs::write(
+            namespace.base_path.join(".git/HEAD"),
+            "ref: refs/heads/main\n",
+        )
+        .unwrap();
+        assert_eq!(
+            render_instances(&namespace).unwrap(),
+            [
+                PathBuf::from("/etc/redis/.htaccess"),
+                PathBuf::from("/etc/redis/redis.conf"),
+            ]
+        );
+    }
+
+    #[test]
+    fn overrides_exclude_defaults() {
+        let overrides: Override = overrides(Path::new("/ns"), &[], &["*.bak".to_owned()]).unwrap();
+        for file in &[
+            "README.md",
+            "eri.conf",
+            ".eriignore",
+            ".eri-state.json",
+            ".gitignore",
+            ".app.conf.eri-tmp",
+            "app.conf~",
+            "app.conf.bak",
+        ] {
+            assert!(overrides.matched(file, false).is_ignore(), "{}", file);
+        }
+        assert!(overrides.matched(".git", true).is_ignore());
+        assert!(!overrides.matched("app.conf", false).is_ignore());
+        assert!(!overrides.matched(".htaccess", false).is_ignore());
+    }
+
+    #[test]
+    fn read_conf_includes() {
+        let dir: TestDir = TestDir::new("namespace-read-conf");
+        std::fs::create_dir_all(dir.join("db")).unwrap();
+        std::fs::write(
+            dir.join("eri.conf"),
+            "port = 80;\n.include \"db/db.conf\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("db/db.conf"),
+            "db = \"$NAMESPACE\";\n.include \"$CURDIR/user.conf\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("db/user.conf"), "user = \"redis\";\n").unwrap();
+        let values: Map<String, Value> = read_conf(&dir.join("eri.conf"), "web").unwrap();
+        assert_eq!(values["port"], 80);
+        assert_eq!(values["db"], "web");
+        // included files include files relative to their directory through $CURDIR
+        assert_eq!(values["user"], "redis");
+    }
+
+    #[test]
+    fn module_data_defaults() {
+        let dir: TestDir = TestDir::new("namespace-module");
+        std::fs::create_dir_all(dir.join("service-base")).unwrap();
+        std::fs::write(
+            dir.join("service-base/eri.conf"),
+            "user = \"$NAMESPACE\";\nport = 80;\neri { copy = [\"*.png\"]; }\n",
+        )
+        .unwrap();
+
+        let mut data: Cow<Map<String, Value>> = Cow::Owned(
+            serde_json::json!({
+                "web": {"port": 8080, "eri": {"extends": "service-base", "recursive": true}},
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        );
+        let module_path: Option<PathBuf> = module(&dir, "web", &mut data).unwrap();
+        assert_eq!(module_path, Some(dir.join("service-base")));
+        assert_eq!(data["web"]["user"], "web");
+        assert_eq!(data["web"]["port"], 8080);
+        assert_eq!(data["web"]["eri"]["copy"], serde_json::json!(["*.png"]));
+        assert_eq!(data["web"]["eri"]["recursive"], true);
+
+        let mut data: Cow<Map<String, Value>> = Cow::Owned(
+            serde_json::json!({"web": {"eri": {"extends": "missing"}}})
+                .as_object()
+                .unwrap()
+                .clone(),
+        );
+        assert!(module(&dir, "web", &mut data).is_err());
+        let mut data: Cow<Map<String, Value>> = Cow::Owned(Map::new());
+        assert_eq!(module(&dir, "web", &mut data).unwrap(), None);
+    }
+
+    #[test]
+    fn declared_data_is_decrypted() {
+        testing::age_identity();
+        let password: String = crypt::encrypt_value("db-pass-4c8e1f", &[]).unwrap();
+        let mut data: Cow<Map<String, Value>> = Cow::Owned(
+            json!({
+                "web": {"port": 80},
+                "db": {"password": password, "user": "db-user-4c8e1f", "eri": {"sensitive": ["user"]}},
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        );
+        decrypt_declared(&mut data, "web").unwrap();
+        assert_eq!(data["db"]["password"], "db-pass-4c8e1f");
+        assert_eq!(
+            sensitive::redact("db-pass-4c8e1f db-user-4c8e1f"),
+            "<redacted> <redacted>"
+        );
+    }
+
+    #[test]
+    fn overrides_include() {
+        let overrides: Override = overrides(Path::new("/ns"), &["*.conf".to_owned()], &[]).unwrap();
+        assert!(overrides.matched("app.conf", false).is_whitelist());
+        assert!(overrides.matched("app.ini", false).is_ignore());
+    }
 }
@@ -0,0 +1,1027 @@
+use crate::accounts;
+use crate::backup::Run;
+use crate::files;
+use crate::manifest;
+use crate::manifest::Manifest;
+use crate::manifest::ManifestEntry;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use users::Group;
+use users::User;
+
+use umask::Mode;
+
+/// A file rendered from a template, ready to be written to an output.
+#[derive(Clone, Debug)]
+pub struct RenderedFile {
+    /// The path of the file, relative to the export directory.
+    pub name: PathBuf,
+    /// The export directory.
+    pub dir: PathBuf,
+    /// The directory the export directory is inside of, if it's not the root directory.
+    pub root: Option<PathBuf>,
+    pub contents: Vec<u8>,
+    pub user: User,
+    pub group: Group,
+    pub mode: Mode,
+}
+
+impl RenderedFile {
+    /// Get the path the file is written to, inside its root directory if there's one.
+    pub fn path(&self) -> Result<PathBuf> {
+        let path: PathBuf = self.dir.join(&self.name);
+        match &self.root {
+            Some(root) => files::join_root(root, &path),
+            None => Ok(path),
+        }
+    }
+}
+
+/// The place rendered files are written to.
+pub trait Output {
+    /// Write a file rendered by a namespace(e.g. `vault` or `vault/instance`).
+    fn write(&mut self, namespace: &str, file: &RenderedFile) -> Result<()>;
+
+    /// Mark a namespace as completely rendered into an export directory, along with the
+    /// files its rendered files depend on besides templates.
+    fn complete(
+        &mut self,
+        _namespace: &str,
+        _dir: &Path,
+        _root: Option<&Path>,
+        _dependencies: &[PathBuf],
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Check whether the output changes files, in which case generated values are saved.
+    fn writes(&self) -> bool {
+        true
+    }
+
+    /// Report the values a namespace generated without saving them, since the output does
+    /// not change files.
+    fn missing_values(&mut self, _namespace: &str, _state: &Path, _names: &[String]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Finish writing, once all the files were written.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Write rendered files to the filesystem, setting their ownership and permissions.
+///
+/// The files are recorded in the manifest of their export directory. Files recorded by a
+/// namespace that it no longer renders are stale, and are removed when pruning if they were
+/// not changed since.
+/// Files are backed up before being changed or removed.
+#[derive(Debug, Default)]
+pub struct Filesystem {
+    write: bool,
+    prune: bool,
+    written: BTreeMap<PathBuf, BTreeMap<String, ManifestEntry>>,
+    complete: BTreeMap<PathBuf, BTreeMap<String, Vec<PathBuf>>>,
+    backup: Option<Run>,
+}
+
+impl Filesystem {
+    /// Write rendered files, pruning the stale ones if asked to.
+    pub fn new(prune: bool, backup: Run) -> Self {
+        Filesystem {
+            write: true,
+            prune,
+            backup: Some(backup),
+            ..Filesystem::default()
+        }
+    }
+
+    /// Only prune the stale files, without writing the rendered ones.
+    pub fn prune_only(backup: Run) -> Self {
+        Filesystem {
+            write: false,
+            prune: true,
+            backup: Some(backup),
+            ..Filesystem::default()
+        }
+    }
+
+    /// Prune or report the stale files of an export directory, then update its manifest.
+    fn finish_dir(&mut self, dir: &Path) -> Result<()> {
+        let mut manifest: Manifest = Manifest::open(dir)?;
+        let empty_written: BTreeMap<String, ManifestEntry> = BTreeMap::new();
+        let written: &BTreeMap<String, ManifestEntry> =
+            self.written.get(dir).unwrap_or(&empty_written);
+        let empty_complete: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        let complete: &BTreeMap<String, Vec<PathBuf>> =
+            self.complete.get(dir).unwrap_or(&empty_complete);
+
+        let stale: Vec<(String, ManifestEntry)> = manifest
+            .files
+            .iter()
+            .filter(|(name, entry)| {
+                complete.contains_key(&entry.namespace) && !written.contains_key(*name)
+            })
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+        let mut changed: bool = false;
+        for (name, entry) in stale {
+            if !self.prune {
+                log::info!(
+                    "{:?} is no longer rendered by namespace {}, run eri prune to remove it",
+                    dir.join(&name),
+                    entry.namespace
+                );
+                continue;
+            }
+            if remove_stale(dir, &name, &entry, self.backup.as_mut())? {
+                manifest.files.remove(&name);
+                changed = true;
+            }
+        }
+
+        if self.write {
+            for (name, entry) in written {
+                let mut entry: ManifestEntry = entry.clone();
+                // a file created by eri before is replaced by eri itself
+                if let Some(previous) = manifest.files.get(name) {
+                    entry.created = entry.created || previous.created;
+                }
+                manifest.files.insert(name.clone(), entry);
+            }
+            for (namespace, dependencies) in complete {
+                manifest
+                    .dependencies
+                    .insert(namespace.clone(), manifest::dependencies(dependencies)?);
+            }
+            changed = true;
+        }
+        if changed {
+            manifest.save(dir)?;
+        }
+        Ok(())
+    }
+}
+
+impl Output for Filesystem {
+    fn writes(&self) -> bool {
+        self.write
+    }
+
+    fn write(&mut self, namespace: &str, file: &RenderedFile) -> Result<()> {
+        let path_dir: PathBuf = export_dir(&file.dir, file.root.as_deref())?;
+        let path_file: PathBuf = file.path()?;
+        let name: String = match file.name.to_str() {
+            Some(value) => value.to_owned(),
+            None => return Err(anyhow!("invalid file name: {:?}", file.name)),
+        };
+        self.written.entry(path_dir.clone()).or_default().insert(
+            name,
+            ManifestEntry {
+                namespace: namespace.to_owned(),
+                sha256: manifest::sha256(&file.contents),
+                uid: file.user.uid(),
+                gid: file.group.gid(),
+                mode: file.mode.into(),
+                created: !path_file.exists(),
+            },
+        );
+        if !self.write {
+            return Ok(());
+        }
+
+        if let Some(root) = &file.root {
+            // the root holds the rendered files, it's not a directory eri exports into
+            std::fs::create_dir_all(root)?;
+        }
+        if path_dir.exists() && !path_dir.is_dir() {
+            return Err(anyhow!("export dir already exists"));
+        }
+        let owner: Option<(&User, &Group)> = if sets_ownership(file) {
+            Some((&file.user, &file.group))
+        } else {
+            log::debug!(
+                "Not changing the ownership of {:?}, eri is not running as root",
+                path_file
+            );
+            None
+        };
+
+        // the missing parents of the export directory are only created inside a root, as
+        // system directories(e.g. `var/lib`) owned by root
+        let root_owner: (User, Group) = (User::new(0, "root", 0), Group::new(0, "root"));
+        let parent_owner: Option<(&User, &Group)> = owner.map(|_| (&root_owner.0, &root_owner.1));
+        let missing_dirs: Vec<&Path> = path_file
+            .parent()
+            .unwrap()
+            .ancestors()
+            .take_while(|dir| {
+                let created: bool = match file.root.as_deref() {
+                    Some(root) => dir.starts_with(root) && *dir != root,
+                    None => dir.starts_with(&path_dir),
+                };
+                created && !dir.exists()
+            })
+            .collect();
+        for dir in missing_dirs.into_iter().rev() {
+            if dir.starts_with(&path_dir) {
+                create_dir(dir, owner, file.mode)?;
+            } else {
+                create_dir(dir, parent_owner, Mode::from(0o755))?;
+            }
+        }
+
+        if path_file.exists() && Verify::compare(&path_file, file)?.is_empty() {
+            log::debug!("{:?} is unchanged", path_file);
+            return Ok(());
+        }
+        if let Some(backup) = self.backup.as_mut() {
+            backup.snapshot(&path_file)?;
+        }
+        // the file replaces the previous one once it's complete, with its owner and mode
+        files::atomic_write(
+            &path_file,
+            &file.contents,
+            file.mode.into(),
+            owner.map(|(user, group)| (user.uid(), group.gid())),
+        )
+    }
+
+    fn complete(
+        &mut self,
+        namespace: &str,
+        dir: &Path,
+        root: Option<&Path>,
+        dependencies: &[PathBuf],
+    ) -> Result<()> {
+        self.complete
+            .entry(export_dir(dir, root)?)
+            .or_default()
+            .insert(namespace.to_owned(), dependencies.to_vec());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let mut dirs: BTreeSet<PathBuf> = self.written.keys().cloned().collect();
+        dirs.extend(self.complete.keys().cloned());
+        for dir in dirs {
+            if !dir.is_dir() {
+                continue;
+            }
+            if let Err(e) = self.finish_dir(&dir) {
+                return Err(anyhow!("failed to update the manifest of {:?}: {}", dir, e));
+            }
+        }
+        match &self.backup {
+            Some(backup) => backup.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A way in which a file on the disk differs from the rendered one.
+#[derive(Clone, Debug)]
+pub struct Difference {
+    /// What differs: `missing`, `type`, `contents`, `owner`, `group`, `mode` or
+    /// `generated value`, for the values missing from a state file.
+    pub kind: &'static str,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.expected, &self.actual) {
+            (Some(expected), Some(actual)) => {
+                write!(f, "{} is {} instead of {}", self.kind, actual, expected)
+            }
+            (Some(expected), None) => write!(f, "{} {} is missing", self.kind, expected),
+            _ => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+/// A file on the disk that differs from the rendered one.
+#[derive(Clone, Debug)]
+pub struct Drift {
+    pub path: PathBuf,
+    pub namespace: String,
+    pub differences: Vec<Difference>,
+}
+
+/// Compare rendered files with the ones on the disk, without writing anything.
+/// The contents of the files are never reported, since they could hold secrets.
+#[derive(Debug, Default)]
+pub struct Verify {
+    /// The number of files compared.
+    pub verified: usize,
+    pub drifts: Vec<Drift>,
+}
+
+impl Verify {
+    /// Compare a rendered file with the one on the disk.
+    fn compare(path: &Path, file: &RenderedFile) -> Result<Vec<Difference>> {
+        use std::os::unix::fs::MetadataExt;
+
+        let difference = |kind: &'static str, expected: String, actual: String| Difference {
+            kind,
+            expected: Some(expected),
+            actual: Some(actual),
+        };
+        let metadata: std::fs::Metadata = match std::fs::symlink_metadata(path) {
+            Ok(value) => value,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(vec![Difference {
+                    kind: "missing",
+                    expected: None,
+                    actual: None,
+                }])
+            }
+            Err(e) => return Err(anyhow!("failed to read {:?}: {}", path, e)),
+        };
+        if !metadata.is_file() {
+            return Ok(vec![difference(
+                "type",
+                "file".to_owned(),
+                format!("{:?}", metadata.file_type()),
+            )]);
+        }
+
+        let mut differences: Vec<Difference> = Vec::new();
+        if std::fs::read(path)? != file.contents {
+            differences.push(Difference {
+                kind: "contents",
+                expected: None,
+                actual: None,
+            });
+        }
+        if sets_ownership(file) {
+            if metadata.uid() != file.user.uid() {
+                differences.push(difference(
+                    "owner",
+                    user_name(file.user.uid()),
+                    user_name(metadata.uid()),
+                ));
+            }
+            if metadata.gid() != file.group.gid() {
+                differences.push(difference(
+                    "group",
+                    group_name(file.group.gid()),
+                    group_name(metadata.gid()),
+                ));
+            }
+        }
+        let mode: u32 = file.mode.into();
+        if metadata.mode() & 0o7777 != mode & 0o7777 {
+            differences.push(difference(
+                "mode",
+                format!("{:o}", mode & 0o7777),
+                format!("{:o}", metadata.mode() & 0o7777),
+            ));
+        }
+        Ok(differences)
+    }
+
+    /// Report the drifts, as json if asked to.
+    pub fn report(&self, json: bool) {
+        if json {
+            let drifts: Vec<serde_json::Value> = self
+                .drifts
+                .iter()
+                .map(|drift| {
+                    serde_json::json!({
+                        "path": drift.path,
+                        "namespace": drift.namespace,
+                        "differences": drift
+                            .differences
+                            .iter()
+                            .map(|difference| serde_json::json!({
+                                "kind": difference.kind,
+                                "expected": difference.expected,
+                                "actual": difference.actual,
+                            }))
+                            .collect::<Vec<serde_json::Value>>(),
+                    })
+                })
+                .collect();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "verified": self.verified,
+                    "drifts": drifts,
+                }))
+                .unwrap()
+            );
+            return;
+        }
+        for drift in &self.drifts {
+            let differences: Vec<String> = drift
+                .differences
+                .iter()
+                .map(Difference::to_string)
+                .collect();
+            log::warn!(
+                "{:?}, rendered by namespace {}, drifted: {}",
+                drift.path,
+                drift.namespace,
+                differences.join(", ")
+            );
+        }
+        if self.drifts.is_empty() {
+            log::info!("Verified {} files, none drifted", self.verified);
+        } else {
+            log::warn!(
+                "Verified {} files, {} drifted",
+                self.verified,
+                self.drifts.len()
+            );
+        }
+    }
+}
+
+impl Output for Verify {
+    fn write(&mut self, namespace: &str, file: &RenderedFile) -> Result<()> {
+        let path: PathBuf = file.path()?;
+        self.verified += 1;
+        let differences: Vec<Difference> = Verify::compare(&path, file)?;
+        if !differences.is_empty() {
+            self.drifts.push(Drift {
+                path,
+                namespace: namespace.to_owned(),
+                differences,
+            });
+        }
+        Ok(())
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn missing_values(&mut self, namespace: &str, state: &Path, names: &[String]) -> Result<()> {
+        self.drifts.push(Drift {
+            path: state.to_path_buf(),
+            namespace: namespace.to_owned(),
+            differences: names
+                .iter()
+                .map(|name| Difference {
+                    kind: "generated value",
+                    expected: Some(name.clone()),
+                    actual: None,
+                })
+                .collect(),
+        });
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Check whether eri sets the ownership of a rendered file: files rendered into another root
+/// by a regular user keep the ownership of the user.
+fn sets_ownership(file: &RenderedFile) -> bool {
+    file.root.is_none() || unsafe { libc::geteuid() } == 0
+}
+
+/// Format a uid along with the name of its user(e.g. `0(root)`).
+fn user_name(uid: u32) -> String {
+    match accounts::user_by_uid(uid) {
+        Some(user) if user.name().to_string_lossy() != uid.to_string() => {
+            format!("{}({})", uid, user.name().to_string_lossy())
+        }
+        _ => uid.to_string(),
+    }
+}
+
+/// Format a gid along with the name of its group(e.g. `0(root)`).
+fn group_name(gid: u32) -> String {
+    match accounts::group_by_gid(gid) {
+        Some(group) if group.name().to_string_lossy() != gid.to_string() => {
+            format!("{}({})", gid, group.name().to_string_lossy())
+        }
+        _ => gid.to_string(),
+    }
+}
+
+/// Get the path of an export directory, inside a root directory if there's one.
+fn export_dir(dir: &Path, root: Option<&Path>) -> Result<PathBuf> {
+    match root {
+        Some(root) => files::join_root(root, dir),
+        None => Ok(dir.to_path_buf()),
+    }
+}
+
+/// Remove a stale file of an export directory, unless it was changed since eri rendered it.
+/// Returns whether the file is gone.
+fn remove_stale(
+    dir: &Path,
+    name: &str,
+    entry: &ManifestEntry,
+    backup: Option<&mut Run>,
+) -> Result<bool> {
+    // the export directory acts as a root, so that a stale file never leads outside of it
+    let path: PathBuf = files::join_root(dir, Path::new(name))?;
+    if !path.exists() {
+        return Ok(true);
+    }
+    if !entry.created {
+        log::info!(
+            "{:?} is no longer rendered by namespace {}, but eri did not create it, not removing it",
+            path,
+            entry.namespace
+        );
+        return Ok(true);
+    }
+    if !path.is_file() || manifest::sha256(&std::fs::read(&path)?) != entry.sha256 {
+        log::warn!(
+            "{:?} is no longer rendered by namespace {}, but it was changed since, not removing it",
+            path,
+            entry.namespace
+        );
+        return Ok(false);
+    }
+    if let Some(backup) = backup {
+        backup.snapshot(&path)?;
+    }
+    std::fs::remove_file(&path)?;
+    log::info!(
+        "Removed {:?}, namespace {} no longer renders it",
+        path,
+        entry.namespace
+    );
+    Ok(true)
+}
+
+/// The file an archive is written to, compressed if its name ends with `.gz` or `.tgz`.
+enum ArchiveFile {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+}
+
+impl Write for ArchiveFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ArchiveFile::Plain(file) => file.write(buf),
+            ArchiveFile::Gzip(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ArchiveFile::Plain(file) => file.flush(),
+            ArchiveFile::Gzip(file) => file.flush(),
+        }
+    }
+}
+
+/// Write rendered files into a tar archive, recording their ownership and permissions in the
+/// archive instead of applying them, so that it can be extracted on the target later on(e.g.
+/// as root, or as an image layer).
+///
+/// Files are archived relative to `/`, along with their export directory and the
+/// directories between them. The export directory and its parents are owned by root with
+/// the 755 mode, since they usually exist on the target already.
+pub struct Archive {
+    path: PathBuf,
+    builder: Option<tar::Builder<ArchiveFile>>,
+    dirs: BTreeSet<PathBuf>,
+    mtime: u64,
+}
+
+impl Archive {
+    /// Create an archive file.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file: File = match File::create(path) {
+            Ok(value) => value,
+            Err(e) => return Err(anyhow!("failed to create archive {:?}: {}", path, e)),
+        };
+        let name: &str = path.to_str().unwrap_or_default();
+        let archive_file: ArchiveFile = if name.ends_with(".gz") || name.ends_with(".tgz") {
+            ArchiveFile::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            ArchiveFile::Plain(file)
+        };
+        Ok(Archive {
+            path: path.to_path_buf(),
+            builder: Some(tar::Builder::new(archive_file)),
+            dirs: BTreeSet::new(),
+            mtime: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        })
+    }
+
+    /// Create the header of an entry of the archive.
+    fn header(
+        &self,
+        user: &User,
+        group: &Group,
+        kind: tar::EntryType,
+        mode: Mode,
+        size: usize,
+    ) -> Result<tar::Header> {
+        let mut header: tar::Header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(size as u64);
+        header.set_mode(mode.into());
+        header.set_uid(user.uid() as u64);
+        header.set_gid(group.gid() as u64);
+        header.set_username(&user.name().to_string_lossy())?;
+        header.set_groupname(&group.name().to_string_lossy())?;
+        header.set_mtime(self.mtime);
+        Ok(header)
+    }
+
+    /// Append an entry to the archive.
+    fn append(&mut self, mut header: tar::Header, path: &Path, contents: &[u8]) -> Result<()> {
+        match self.builder.as_mut() {
+            Some(builder) => builder.append_data(&mut header, path, contents)?,
+            None => return Err(anyhow!("archive {:?} is already finished", self.path)),
+        }
+        Ok(())
+    }
+}
+
+impl Output for Archive {
+    fn write(&mut self, _namespace: &str, file: &RenderedFile) -> Result<()> {
+        let root: PathBuf = file.root.clone().unwrap_or_default();
+        let path_dir: PathBuf = root.join(file.dir.strip_prefix("/").unwrap_or(&file.dir));
+
+        // the export directory and its parents usually exist on the target(e.g. `etc`), so
+        // they are neutral entries, which do not change them when extracted as root
+        let mut dirs: Vec<(PathBuf, bool)> = path_dir
+            .ancestors()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| (dir.to_path_buf(), false))
+            .collect();
+        dirs.reverse();
+        if let Some(parent) = file.name.parent() {
+            let mut path: PathBuf = path_dir.clone();
+            for component in parent.components() {
+                path.push(component);
+                dirs.push((path.clone(), true));
+            }
+        }
+        let (root_user, root_group) = (User::new(0, "root", 0), Group::new(0, "root"));
+        for (dir, exported) in dirs {
+            if !self.dirs.insert(dir.clone()) {
+                continue;
+            }
+            let header: tar::Header = if exported {
+                self.header(
+                    &file.user,
+                    &file.group,
+                    tar::EntryType::Directory,
+                    dir_mode(file.mode),
+                    0,
+                )?
+            } else {
+                self.header(
+                    &root_user,
+                    &root_group,
+                    tar::EntryType::Directory,
+                    Mode::from(0o755),
+                    0,
+                )?
+            };
+            self.append(header, &dir, &[])?;
+        }
+
+        let header: tar::Header = self.header(
+            &file.user,
+            &file.group,
+            tar::EntryType::Regular,
+            file.mode,
+            file.contents.len(),
+        )?;
+        self.append(header, &path_dir.join(&file.name), &file.contents)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let builder: tar::Builder<ArchiveFile> = match self.builder.take() {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        match builder.into_inner()? {
+            ArchiveFile::Plain(mut file) => file.flush()?,
+            ArchiveFile::Gzip(file) => {
+                file.finish()?;
+            }
+        }
+        log::info!("Rendered files were archived into {:?}", self.path);
+        Ok(())
+    }
+}
+
+fn create_dir(path: &Path, owner: Option<(&User, &Group)>, mode: Mode) -> Result<()> {
+    std::fs::create_dir(path)?;
+    if let Some((user, group)) = owner {
+        chown(path, user.uid(), group.gid())?;
+    }
+    chmod(path, dir_mode(mode))
+}
+
+/// Get the permissions of a directory holding files with some permissions: the files
+/// permissions, searchable by whoever can access the files.
+fn dir_mode(mode: Mode) -> Mode {
+    let mut dir_mode: Mode = mode;
+    if !dir_mode.has(umask::USER_EXEC) {
+        dir_mode = dir_mode.with(umask::USER_EXEC);
+    }
+    if !dir_mode.has(umask::GROUP_EXEC) {
+        dir_mode = dir_mode.with(umask::GROUP_EXEC);
+    }
+    if !dir_mode.has(umask::OTHERS_EXEC) {
+        dir_mode = dir_mode.with(umask::OTHERS_EXEC);
+    }
+    dir_mode
+}
+
+pub(crate) fn chown(path: &Path, uid: u32, gid: u32) -> Result<()> {
+    let cstr_path: CString = CString::new(path.to_str().unwrap()).unwrap();
+    let ret_val: libc::c_int = unsafe { libc::chown(cstr_path.as_ptr(), uid, gid) };
+    if ret_val == -1 {
+        let errno_val: i32 = errno::errno().into();
+        match errno_val {
+            libc::EPERM => {
+                return Err(anyhow!(
+                    "chown: this process lacks permission to make the requested change"
+                ))
+            }
+            libc::EROFS => return Err(anyhow!("chown: the file is on a read-only file system")),
+            _ => panic!("chown: unexpected errno: {}", errno_val),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn chmod(path: &Path, mode: Mode) -> Result<()> {
+    let cstr_path: CString = CString::new(path.to_str().unwrap()).unwrap();
+    let ret_val: libc::c_int = unsafe { libc::chmod(cstr_path.as_ptr(), mode.into()) };
+    if ret_val == -1 {
+        let errno_val: i32 = errno::errno().into();
+        match errno_val {
+            libc::ENOENT => return Err(anyhow!("chmod: the named file doesn’t exist")),
+            libc::EPERM => {
+                return Err(anyhow!(
+                    "chmod: this process does not have permission to change the access permissions of this file"
+                ))
+            }
+            libc::EROFS => {
+                return Err(anyhow!("chmod: the file resides on a read-only file system"))
+            }
+            _ => panic!("chmod: unexpected errno: {}", errno_val),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::BackupConfig;
+    use crate::testing::TestDir;
+
+    use std::io::Read;
+
+    /// Get a file rendered into an export directory.
+    fn rendered(name: &str, contents: &str) -> RenderedFile {
+        RenderedFile {
+            name: PathBuf::from(name),
+            dir: PathBuf::from("/etc/redis"),
+            root: None,
+            contents: contents.as_bytes().to_vec(),
+            user: User::new(100, "redis", 101),
+            group: Group::new(101, "redis"),
+            mode: Mode::from(0o640),
+        }
+    }
+
+    /// Get the manifest entry of a file rendered by eri.
+    fn entry(contents: &str, created: bool) -> ManifestEntry {
+        ManifestEntry {
+            namespace: "redis".to_owned(),
+            sha256: manifest::sha256(contents.as_bytes()),
+            uid: 100,
+            gid: 101,
+            mode: 0o640,
+            created,
+        }
+    }
+
+    #[test]
+    fn remove_stale_files() {
+        let dir: TestDir = TestDir::new("output-stale");
+        for name in &["created.conf", "changed.conf", "replaced.conf"] {
+            std::fs::write(dir.join(name), "port 6379\n").unwrap();
+        }
+
+        assert!(remove_stale(&dir, "created.conf", &entry("port 6379\n", true), None).unwrap());
+        assert!(!dir.join("created.conf").exists());
+        assert!(!remove_stale(&dir, "changed.conf", &entry("port 6380\n", true), None).unwrap());
+        assert!(dir.join("changed.conf").exists());
+        assert!(remove_stale(&dir, "replaced.conf", &entry("port 6379\n", false), None).unwrap());
+        assert!(dir.join("replaced.conf").exists());
+        assert!(remove_stale(&dir, "missing.conf", &entry("", true), None).unwrap());
+        // the export directory is the root of stale files
+        assert!(remove_stale(
+            &dir,
+            &format!(
+                "../{}/replaced.conf",
+                dir.file_name().unwrap().to_str().unwrap()
+            ),
+            &entry("port 6379\n", true),
+            None
+        )
+        .unwrap());
+        assert!(dir.join("replaced.conf").exists());
+    }
+
+    #[test]
+    fn write_into_root_creates_dirs() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir: TestDir = TestDir::new("output-root");
+        let backup: BackupConfig = BackupConfig {
+            dir: Some(dir.join("backups").to_str().unwrap().to_owned()),
+            keep: None,
+            keep_days: None,
+        };
+        let mut output: Filesystem = Filesystem::new(false, Run::start(&backup, "render").unwrap());
+        let root: PathBuf = dir.join("root");
+        let mut file: RenderedFile = rendered("redis.conf", "port 6379\n");
+        file.dir = PathBuf::from("/var/lib/redis/conf");
+        file.root = Some(root.clone());
+        output.write("redis", &file).unwrap();
+
+        assert!(root.join("var/lib/redis/conf/redis.conf").is_file());
+        // only the export directory belongs to the owner of the files, not its parents
+        let as_root: bool = users::get_current_uid() == 0;
+        for (created, mode, uid) in &[
+            ("var", 0o755, 0),
+            ("var/lib", 0o755, 0),
+            ("var/lib/redis", 0o755, 0),
+            ("var/lib/redis/conf", 0o751, 100),
+        ] {
+            let metadata: std::fs::Metadata = std::fs::metadata(root.join(created)).unwrap();
+            assert_eq!(metadata.mode() & 0o7777, *mode, "{}", created);
+            if as_root {
+                assert_eq!(metadata.uid(), *uid, "{}", created);
+            }
+        }
+    }
+
+    #[test]
+    fn write_replaces_files() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir: TestDir = TestDir::new("output-replace");
+        let backup: BackupConfig = BackupConfig {
+            dir: Some(dir.join("backups").to_str().unwrap().to_owned()),
+            keep: None,
+            keep_days: None,
+        };
+        let mut output: Filesystem = Filesystem::new(false, Run::start(&backup, "render").unwrap());
+        std::fs::write(dir.join("redis.conf"), "port 6380\n").unwrap();
+        std::fs::hard_link(dir.join("redis.conf"), dir.join("previous.conf")).unwrap();
+        let mut file: RenderedFile = rendered("redis.conf", "port 6379\n");
+        file.dir = dir.to_path_buf();
+        file.user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+        file.group = users::get_group_by_gid(users::get_current_gid()).unwrap();
+        output.write("redis", &file).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dir.join("redis.conf")).unwrap(),
+            "port 6379\n"
+        );
+        let mode: u32 = std::fs::metadata(dir.join("redis.conf")).unwrap().mode();
+        assert_eq!(mode & 0o7777, 0o640);
+        // the previous file was replaced instead of being written over
+        assert_eq!(
+            std::fs::read_to_string(dir.join("previous.conf")).unwrap(),
+            "port 6380\n"
+        );
+        assert!(!dir.join(".redis.conf.eri-tmp").exists());
+    }
+
+    #[test]
+    fn verify_drifts() {
+        let dir: TestDir = TestDir::new("output-verify");
+        std::fs::write(dir.join("redis.conf"), "port 6380\n").unwrap();
+        chmod(&dir.join("redis.conf"), Mode::from(0o600)).unwrap();
+
+        let mut verify: Verify = Verify::default();
+        for name in &["redis.conf", "missing.conf"] {
+            let mut file: RenderedFile = rendered(name, "port 6379\n");
+            file.dir = dir.to_path_buf();
+            file.user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+            file.group = users::get_group_by_gid(users::get_current_gid()).unwrap();
+            verify.write("redis", &file).unwrap();
+        }
+        verify
+            .missing_values(
+                "redis",
+                &dir.join(".eri-state.json"),
+                &["password".to_owned()],
+            )
+            .unwrap();
+        assert!(!verify.writes());
+        assert_eq!(verify.verified, 2);
+
+        let drifts: Vec<(PathBuf, Vec<String>)> = verify
+            .drifts
+            .iter()
+            .map(|drift| {
+                let differences: Vec<String> = drift
+                    .differences
+                    .iter()
+                    .map(Difference::to_string)
+                    .collect();
+                (drift.path.clone(), differences)
+            })
+            .collect();
+        assert_eq!(
+            drifts,
+            [
+                (
+                    dir.join("redis.conf"),
+                    vec![
+                        "contents".to_owned(),
+                        "mode is 600 instead of 640".to_owned()
+                    ]
+                ),
+                (dir.join("missing.conf"), vec!["missing".to_owned()]),
+                (
+                    dir.join(".eri-state.json"),
+                    vec!["generated value password is missing".to_owned()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn archive_entries() {
+        let dir: TestDir = TestDir::new("output-archive");
+        let path: PathBuf = dir.join("archive.tar.gz");
+        let mut archive: Archive = Archive::create(&path).unwrap();
+        archive
+            .write("redis", &rendered("redis.conf", "port 6379\n"))
+            .unwrap();
+        archive
+            .write("redis", &rendered("conf.d/cache.conf", "maxmemory 1g\n"))
+            .unwrap();
+        archive.finish().unwrap();
+
+        let file: File = File::open(&path).unwrap();
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let mut entries: Vec<(String, u32, u64, String)> = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let header: &tar::Header = entry.header();
+            let (mode, uid) = (header.mode().unwrap(), header.uid().unwrap());
+            let name: String = entry.path().unwrap().to_string_lossy().into_owned();
+            if name == "etc" {
+                assert_eq!(header.gid().unwrap(), 0);
+                assert_eq!(header.username().unwrap(), Some("root"));
+                assert_eq!(header.groupname().unwrap(), Some("root"));
+            }
+            let mut contents: String = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            entries.push((name, mode, uid, contents));
+        }
+        assert_eq!(
+            entries,
+            [
+                ("etc".to_owned(), 0o755, 0, String::new()),
+                ("etc/redis".to_owned(), 0o755, 0, String::new()),
+                (
+                    "etc/redis/redis.conf".to_owned(),
+                    0o640,
+                    100,
+                    "port 6379\n".to_owned()
+                ),
+                ("etc/redis/conf.d".to_owned(), 0o751, 100, String::new()),
+                (
+                    "etc/redis/conf.d/cache.conf".to_owned(),
+                    0o640,
+                    100,
+                    "maxmemory 1g\n".to_owned()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+use globset::GlobBuilder;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+
+use serde_json::Value;
+
+/// The text that replaces sensitive values.
+const REDACTED: &str = "<redacted>";
+
+/// Patterns of the data keys whose values are always sensitive.
+const DEFAULT_KEY_PATTERNS: &[&str] = &[
+    "*password*",
+    "*passwd*",
+    "*passphrase*",
+    "*secret*",
+    "*token*",
+    "*credential*",
+    "key",
+    "*_key",
+    "*-key",
+];
+
+/// The length under which values are not redacted, as they would be replaced everywhere
+/// they appear in the output(e.g. `80` or `yes`).
+const MIN_LENGTH: usize = 4;
+
+/// The sensitive values known so far.
+static VALUES: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Whether sensitive values are shown instead of being redacted.
+static SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Show sensitive values instead of redacting them.
+pub fn show() {
+    SHOWN.store(true, Ordering::Relaxed);
+}
+
+/// Mark all the strings inside a value as sensitive.
+pub fn mark(value: &Value) {
+    match value {
+        Value::String(value) => mark_str(value),
+        Value::Array(array) => array.iter().for_each(mark),
+        Value::Object(map) => map.values().for_each(mark),
+        _ => {}
+    }
+}
+
+/// Mark a string as sensitive, unless it's too short to be redacted.
+pub fn mark_str(value: &str) {
+    if value.chars().count() < MIN_LENGTH {
+        return;
+    }
+    let mut values = VALUES.lock().unwrap();
+    values.insert(value.to_owned());
+    // debug output escapes strings, so their escaped form is sensitive as well
+    let escaped: String = format!("{:?}", value);
+    values.insert(escaped[1..escaped.len() - 1].to_owned());
+}
+
+/// Mark the values of the keys matching the default patterns or one of the given patterns
+/// as sensitive.
+///
+/// Patterns are matched, ignoring case, against both the name of a key and its path
+/// inside the value(e.g. `db.password`).
+pub fn mark_keys(value: &Value, patterns: &[String]) -> Result<()> {
+    let mut builder: GlobSetBuilder = GlobSetBuilder::new();
+    for pattern in DEFAULT_KEY_PATTERNS
+        .iter()
+        .copied()
+        .chain(patterns.iter().map(String::as_str))
+    {
+        match GlobBuilder::new(pattern)
+            .case_insensitive(true)
+            .literal_separator(false)
+            .build()
+        {
+            Ok(glob) => builder.add(glob),
+            Err(e) => return Err(anyhow!("invalid sensitive key pattern {}: {}", pattern, e)),
+        };
+    }
+    mark_matching(value, "", &builder.build()?);
+    Ok(())
+}
+
+/// Mark the values of the keys matching a set of patterns as sensitive.
+fn mark_matching(value: &Value, path: &str, patterns: &GlobSet) {
+    match value {
+        Value::Array(array) => {
+            for (index, item) in array.iter().enumerate() {
+                mark_matching(item, &format!("{}.{}", path, index), patterns);
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map {
+                let item_path: String = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                if patterns.is_match(key) || patterns.is_match(&item_path) {
+                    mark(item);
+                } else {
+                    mark_matching(item, &item_path, patterns);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replace all the sensitive values inside a text, unless they are shown.
+pub fn redact(text: &str) -> String {
+    if SHOWN.load(Ordering::Relaxed) {
+        return text.to_owned();
+    }
+    let values = VALUES.lock().unwrap();
+    let mut values: Vec<&String> = values.iter().collect();
+    // longer values first, so that values containing other values are fully replaced
+    values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+
+    let mut result: String = text.to_owned();
+    for value in values {
+        if result.contains(value.as_str()) {
+            result = result.replace(value.as_str(), REDACTED);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn mark_keys_by_pattern() {
+        let value: Value = json!({
+            "db": {"password": "pw-0b9f3a", "primary_key_column": "id-col-0b9f3a", "user": "user-0b9f3a"},
+            "api_key": "ak-0b9f3a",
+            "keyboard": "kb-0b9f3a",
+            "tls": {"key": "tk-0b9f3a", "keys": ["ks-0b9f3a"]},
+        });
+        mark_keys(&value, &["db.user".to_owned()]).unwrap();
+        assert_eq!(
+            redact("pw-0b9f3a ak-0b9f3a tk-0b9f3a user-0b9f3a"),
+            "<redacted> <redacted> <redacted> <redacted>"
+        );
+        assert_eq!(
+            redact("id-col-0b9f3a kb-0b9f3a ks-0b9f3a"),
+            "id-col-0b9f3a kb-0b9f3a ks-0b9f3a"
+        );
+        assert!(mark_keys(&value, &["[".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn redact_skips_short_values() {
+        mark(&json!(["on", "443", "long-7c1e2d"]));
+        assert_eq!(redact("on 443 long-7c1e2d"), "on 443 <redacted>");
+    }
+
+    #[test]
+    fn redact_escaped_values() {
+        mark_str("quote\"5d0a41");
+        assert_eq!(redact(&format!("{:?}", "quote\"5d0a41")), "\"<redacted>\"");
+    }
+}
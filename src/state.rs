@@ -0,0 +1,242 @@
+use crate::files;
+use crate::sensitive;
+
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+use serde_json::Map;
+use serde_json::Value;
+
+/// The name of the file inside a namespace directory where its generated values are kept.
+pub const STATE_FILE_NAME: &str = ".eri-state.json";
+
+/// Values generated once and kept across renders in a state file.
+pub struct State {
+    path: PathBuf,
+    host: Option<String>,
+    instance: Option<String>,
+    read_only: bool,
+    values: Mutex<Option<Map<String, Value>>>,
+    missing: Mutex<Vec<String>>,
+}
+
+impl State {
+    /// Create the state kept in a file.
+    /// The values of each host of an inventory and of each instance of a namespace are kept
+    /// separately, under keys prefixed by their scope(e.g. `host:cache1/instance:queue/key`).
+    /// The file is only read when a value is first needed, and never written if the state
+    /// is read-only(e.g. when verifying files).
+    pub fn new(
+        path: PathBuf,
+        host: Option<String>,
+        instance: Option<String>,
+        read_only: bool,
+    ) -> Self {
+        State {
+            path,
+            host,
+            instance,
+            read_only,
+            values: Mutex::new(None),
+            missing: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Get the path of the state file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the names of the values that were generated without being saved, since the
+    /// state is read-only.
+    pub fn missing(&self) -> Vec<String> {
+        self.missing.lock().unwrap().clone()
+    }
+
+    /// Get a value, generating and saving it if it does not exist yet.
+    pub fn get_or_generate<F>(&self, name: &str, generate: F) -> Result<String>
+    where
+        F: FnOnce() -> Result<String>,
+    {
+        let mut values = self.values.lock().unwrap();
+        if values.is_none() {
+            *values = Some(load(&self.path)?);
+        }
+        let values: &mut Map<String, Value> = values.as_mut().unwrap();
+        // the name is the last part of a key, so that keys of different scopes never collide
+        if name.contains('/') {
+            return Err(anyhow!("the name of generated value {} contains a /", name));
+        }
+        let name: String = match (&self.host, &self.instance) {
+            (Some(host), Some(instance)) => format!("host:{}/instance:{}/{}", host, instance, name),
+            (Some(host), None) => format!("host:{}/{}", host, name),
+            (None, Some(instance)) => format!("instance:{}/{}", instance, name),
+            (None, None) => name.to_owned(),
+        };
+
+        if let Some(value) = values.get(&name) {
+            return match value.as_str() {
+                Some(value) => Ok(value.to_owned()),
+                None => Err(anyhow!(
+                    "value {} of state file {:?} is not a string",
+                    name,
+                    self.path
+                )),
+            };
+        }
+
+        let value: String = generate()?;
+        sensitive::mark_str(&value);
+        values.insert(name.clone(), Value::String(value.clone()));
+        if self.read_only {
+            log::debug!("Value {} is missing from {:?}", name, self.path);
+            self.missing.lock().unwrap().push(name);
+            return Ok(value);
+        }
+        save(&self.path, values)?;
+        log::info!("Generated value {} saved in {:?}", name, self.path);
+        Ok(value)
+    }
+}
+
+/// Load the values of a state file, if it exists.
+fn load(path: &Path) -> Result<Map<String, Value>> {
+    if !path.exists() {
+        return Ok(Map::new());
+    }
+    let src: String = match std::fs::read_to_string(path) {
+        Ok(value) => value,
+        Err(e) => return Err(anyhow!("failed to read state file {:?}: {}", path, e)),
+    };
+    match serde_json::from_str(&src) {
+        Ok(Value::Object(value)) => {
+            value.values().for_each(sensitive::mark);
+            Ok(value)
+        }
+        Ok(_) => Err(anyhow!("state file {:?} does not contain an object", path)),
+        Err(e) => Err(anyhow!("failed to parse state file {:?}: {}", path, e)),
+    }
+}
+
+/// Save values into a state file readable only by the current user, and ignored by git.
+fn save(path: &Path, values: &Map<String, Value>) -> Result<()> {
+    let contents: String = serde_json::to_string_pretty(values)?;
+    files::atomic_write(path, contents.as_bytes(), 0o600, None)?;
+    ignore(path)
+}
+
+/// Add a state file to the `.gitignore` file of its directory, unless it's there already.
+fn ignore(path: &Path) -> Result<()> {
+    let (dir, file_name) = match (
+        path.parent(),
+        path.file_name().and_then(|name| name.to_str()),
+    ) {
+        (Some(dir), Some(file_name)) => (dir, file_name),
+        _ => return Err(anyhow!("invalid state file path: {:?}", path)),
+    };
+    let gitignore: PathBuf = dir.join(".gitignore");
+    let entry: String = format!("/{}", file_name);
+    let contents: String = match std::fs::read_to_string(&gitignore) {
+        Ok(value) => value,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(anyhow!("failed to read {:?}: {}", gitignore, e)),
+    };
+    if contents.lines().any(|line| line.trim() == entry) {
+        return Ok(());
+    }
+
+    let mut file: std::fs::File = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&gitignore)?;
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        file.write_all(b"\n")?;
+    }
+    file.write_all(format!("{}\n", entry).as_bytes())?;
+    log::info!("State file {:?} added to {:?}", path, gitignore);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    #[test]
+    fn read_only_state() {
+        let dir: TestDir = TestDir::new("state-read-only");
+        let path: PathBuf = dir.join(STATE_FILE_NAME);
+        std::fs::write(&path, "{\"instance:cache/password\": \"saved-3f9a1c\"}").unwrap();
+
+        let state: State = State::new(path.clone(), None, Some("cache".to_owned()), true);
+        let saved: String = state
+            .get_or_generate("password", || Ok("new-3f9a1c".to_owned()))
+            .unwrap();
+        assert_eq!(saved, "saved-3f9a1c");
+        let generated: String = state
+            .get_or_generate("key", || Ok("new-8b2e4d".to_owned()))
+            .unwrap();
+        assert_eq!(generated, "new-8b2e4d");
+        // the value is kept for the rest of the render, without being saved
+        let again: String = state
+            .get_or_generate("key", || Ok("other-8b2e4d".to_owned()))
+            .unwrap();
+        assert_eq!(again, "new-8b2e4d");
+        assert_eq!(state.missing(), ["instance:cache/key"]);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"instance:cache/password\": \"saved-3f9a1c\"}"
+        );
+        assert!(!dir.join(".gitignore").exists());
+    }
+
+    #[test]
+    fn scopes_do_not_collide() {
+        let dir: TestDir = TestDir::new("state-scopes");
+        let path: PathBuf = dir.join(STATE_FILE_NAME);
+        let scopes: &[(Option<&str>, Option<&str>)] = &[
+            (Some("x"), None),
+            (None, Some("x")),
+            (Some("x"), Some("x")),
+            (None, None),
+        ];
+        for (index, (host, instance)) in scopes.iter().enumerate() {
+            let state: State = State::new(
+                path.clone(),
+                host.map(str::to_owned),
+                instance.map(str::to_owned),
+                false,
+            );
+            let value: String = state
+                .get_or_generate("password", || Ok(format!("pw-{}-7a3c", index)))
+                .unwrap();
+            assert_eq!(value, format!("pw-{}-7a3c", index));
+        }
+        let state: State = State::new(path, None, None, false);
+        assert!(state
+            .get_or_generate("x/password", || Ok("pw-7a3c".to_owned()))
+            .is_err());
+    }
+
+    #[test]
+    fn state_file_is_ignored() {
+        let dir: TestDir = TestDir::new("state-ignored");
+        std::fs::write(dir.join(".gitignore"), "*.bak").unwrap();
+        let state: State = State::new(dir.join(STATE_FILE_NAME), None, None, false);
+        state
+            .get_or_generate("password", || Ok("new-5e7c0a".to_owned()))
+            .unwrap();
+        state
+            .get_or_generate("key", || Ok("new-1d4b9f".to_owned()))
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join(".gitignore")).unwrap(),
+            "*.bak\n/.eri-state.json\n"
+        );
+    }
+}
@@ -1,10 +1,11 @@
 use crate::config::ExportConfig;
 use crate::data;
+use crate::frontmatter;
+use crate::frontmatter::FrontMatter;
+use crate::output::RenderedFile;
 
 use std::borrow::Cow;
-use std::ffi::CString;
-use std::fs::File;
-use std::io::Write;
+use std::path::Component;
 use std::path::PathBuf;
 
 use anyhow::Result;
@@ -15,16 +16,83 @@ use handlebars::template::TemplateElement as HandlebarsTemplateElement;
 use handlebars::Handlebars;
 use handlebars::Path as HandlebarsPath;
 
+use serde::ser::SerializeMap;
+use serde::Serialize;
+use serde::Serializer;
+
 use serde_json::Map;
 use serde_json::Value;
 
+/// The extensions marking a file as a template, stripped from the rendered file name.
+const TEMPLATE_EXTENSIONS: &[&str] = &[".tmpl", ".hbs"];
+
+/// The key under which the element of the list a template iterates over is available, if
+/// it's not an object.
+const ITEM_KEY: &str = "item";
+
+/// The way in which a template file is exported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// The file is rendered using handlebars.
+    Template,
+    /// The file is copied byte for byte.
+    Copy,
+}
+
 /// A template that can be rendered.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Template<'a> {
     pub name: String,
     pub path: PathBuf,
-    pub data: &'a Map<String, Value>,
+    pub data: Cow<'a, Map<String, Value>>,
     pub export_config: Cow<'a, ExportConfig>,
+    pub front_matter: FrontMatter,
+    pub kind: Kind,
+    /// The element of the list this template iterates over, if it has a `foreach` declaration.
+    pub item: Option<Value>,
+    source: Vec<u8>,
+}
+
+/// The data a template is rendered with: the data of its namespace, along with the element
+/// of its list.
+/// The keys of an element that is an object are merged into the data, taking precedence
+/// over it, while any other element is available under `item`.
+struct Context<'a> {
+    data: &'a Map<String, Value>,
+    item: Option<&'a Value>,
+}
+
+impl Context<'_> {
+    /// Get the keys the element of the list provides.
+    fn item_keys(&self) -> Vec<&str> {
+        match self.item {
+            Some(Value::Object(item)) => item.keys().map(String::as_str).collect(),
+            Some(_) => vec![ITEM_KEY],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Serialize for Context<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let item_keys: Vec<&str> = self.item_keys();
+        let mut map = serializer.serialize_map(None)?;
+        for (key, value) in self.data {
+            if !item_keys.contains(&key.as_str()) {
+                map.serialize_entry(key, value)?;
+            }
+        }
+        match self.item {
+            Some(Value::Object(item)) => {
+                for (key, value) in item {
+                    map.serialize_entry(key, value)?;
+                }
+            }
+            Some(item) => map.serialize_entry(ITEM_KEY, item)?,
+            None => {}
+        }
+        map.end()
+    }
 }
 
 impl<'a> Template<'a> {
@@ -32,13 +100,65 @@ impl<'a> Template<'a> {
     pub fn new(
         name: String,
         path: PathBuf,
-        data: &'a Map<String, Value>,
+        data: Cow<'a, Map<String, Value>>,
         mut export_config: Cow<'a, ExportConfig>,
+        mut front_matter: FrontMatter,
     ) -> Result<Self> {
         if path.is_dir() {
             panic!("template is not supposed to be created with a directory path");
         }
 
+        let contents: Vec<u8> = std::fs::read(&path)?;
+        let source: Vec<u8> = match std::str::from_utf8(&contents) {
+            Ok(_) if front_matter.kind() == Some(Kind::Copy) => contents,
+            Ok(template_src) => match frontmatter::split(template_src) {
+                Ok((Some(front_matter_src), content)) => {
+                    match FrontMatter::parse(front_matter_src) {
+                        Ok(value) => {
+                            front_matter.merge(value);
+                            content.as_bytes().to_vec()
+                        }
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "failed to parse the front matter of template {}: {}",
+                                name,
+                                e
+                            ))
+                        }
+                    }
+                }
+                Ok((None, _)) => contents,
+                Err(e) => return Err(anyhow!("template {}: {}", name, e)),
+            },
+            Err(_) => contents,
+        };
+        if let Err(e) = front_matter.validate() {
+            return Err(anyhow!("template {}: {}", name, e));
+        }
+
+        let kind: Kind = match front_matter.kind() {
+            Some(value) => value,
+            None if TEMPLATE_EXTENSIONS.iter().any(|ext| name.ends_with(ext)) => Kind::Template,
+            None if std::str::from_utf8(&source).is_err() => {
+                log::debug!("Template {} is not valid UTF-8, it will be copied", name);
+                Kind::Copy
+            }
+            None => Kind::Template,
+        };
+        if kind == Kind::Template && std::str::from_utf8(&source).is_err() {
+            return Err(anyhow!("template {} is not valid UTF-8", name));
+        }
+
+        if let Some(user) = &front_matter.user {
+            export_config.to_mut().user = Some(user.clone());
+        }
+        if let Some(group) = &front_matter.group {
+            export_config.to_mut().group = Some(group.clone());
+        }
+        if let Some(permissions) = front_matter.permissions {
+            export_config.to_mut().permissions = Some(permissions);
+        }
+
         if export_config.user.is_none() {
             export_config.to_mut().user = Some(data::get_user(&path)?);
         }
@@ -54,81 +174,220 @@ impl<'a> Template<'a> {
             path,
             data,
             export_config,
+            front_matter,
+            kind,
+            item: None,
+            source,
         })
     }
 
+    /// Expand this template into the templates that should actually be rendered.
+    /// A template with a `foreach` declaration produces one template for each element
+    /// of its list, while any other template produces itself.
+    pub fn expand(&self) -> Result<Vec<Template<'a>>> {
+        let foreach: &str = match &self.front_matter.foreach {
+            Some(value) => value,
+            None => return Ok(vec![self.clone()]),
+        };
+        let items: Vec<Value> = match data::lookup(&self.data, foreach) {
+            Some(Value::Array(value)) => value.clone(),
+            Some(_) => {
+                return Err(anyhow!(
+                    "template {} iterates over {}, which is not a list",
+                    self.name,
+                    foreach
+                ))
+            }
+            None => {
+                return Err(anyhow!(
+                    "template {} iterates over {}, which is missing",
+                    self.name,
+                    foreach
+                ))
+            }
+        };
+
+        let mut templates: Vec<Template<'a>> = Vec::new();
+        for item in items {
+            let mut template: Template<'a> = self.clone();
+            template.item = Some(item);
+            templates.push(template);
+        }
+        Ok(templates)
+    }
+
     /// Register this template in a handlebars object.
     pub fn register(&self, handlebars: &mut Handlebars) -> Result<()> {
-        let template_src: String = std::fs::read_to_string(&self.path)?;
-        handlebars.register_template_string(&self.name, template_src)?;
+        if self.kind == Kind::Template {
+            let template_src: &str = std::str::from_utf8(&self.source)?;
+            handlebars.register_template_string(&self.name, template_src)?;
+        }
         Ok(())
     }
 
     /// Render this template using the handlebars object.
-    pub fn render(&self, handlebars: &mut Handlebars) -> Result<()> {
-        log::debug!("Rendering template {}", self.name);
-        let template_rendered_string: String = handlebars.render(&self.name, &self.data)?;
-
-        let user: &users::User = self.export_config.user.as_ref().unwrap();
-        let group: &users::Group = self.export_config.group.as_ref().unwrap();
-        let mode: umask::Mode = self.export_config.permissions.unwrap();
-
-        let path_dir: PathBuf = PathBuf::from(self.export_config.dir.as_ref().unwrap());
-        if !path_dir.exists() {
-            std::fs::create_dir(&path_dir)?;
-            chown(&path_dir, user, group)?;
-            let dir_mode: umask::Mode = {
-                let mut dir_mode: umask::Mode = mode;
-                if !dir_mode.has(umask::USER_EXEC) {
-                    dir_mode = dir_mode.with(umask::USER_EXEC);
-                }
-                if !dir_mode.has(umask::GROUP_EXEC) {
-                    dir_mode = dir_mode.with(umask::GROUP_EXEC);
-                }
-                if !dir_mode.has(umask::OTHERS_EXEC) {
-                    dir_mode = dir_mode.with(umask::OTHERS_EXEC);
-                }
-                dir_mode
-            };
-            chmod(&path_dir, dir_mode)?;
-        } else if !path_dir.is_dir() {
-            return Err(anyhow!("export dir already exists"));
+    /// Nothing is rendered if the condition of the template is not met or if it's skipped.
+    pub fn render(&self, handlebars: &mut Handlebars) -> Result<Option<RenderedFile>> {
+        log::debug!("Rendering template {} from {:?}", self.name, self.path);
+        handlebars.register_escape_fn(handlebars::no_escape);
+        if !self.condition(handlebars)? {
+            log::debug!("Template {} skipped, its condition is not met", self.name);
+            return Ok(None);
         }
+        let missing: Vec<&str> = self.missing_requirements();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "template {} requires missing data: {}",
+                self.name,
+                missing.join(", ")
+            ));
+        }
+        let filename: PathBuf = self.output_filename(handlebars)?;
 
-        let path_file: PathBuf = path_dir.join(self.filename());
-        let mut file: File = File::create(&path_file)?;
-        chown(&path_file, user, group)?;
-        chmod(&path_file, mode)?;
-
-        write!(file, "{}", template_rendered_string)?;
+        let contents: Vec<u8> = match self.kind {
+            Kind::Template => {
+                handlebars.register_escape_fn(self.front_matter.escape_fn());
+                let template_rendered_string: String =
+                    handlebars.render(&self.name, &self.context())?;
+                if self.front_matter.skip_empty == Some(true)
+                    && template_rendered_string.trim().is_empty()
+                {
+                    log::debug!("Template {} rendered empty, skipping", self.name);
+                    return Ok(None);
+                }
+                template_rendered_string.into_bytes()
+            }
+            Kind::Copy => self.source.clone(),
+        };
 
-        Ok(())
+        Ok(Some(RenderedFile {
+            name: filename,
+            dir: PathBuf::from(self.export_config.dir.as_ref().unwrap()),
+            root: self.export_config.root.as_ref().map(PathBuf::from),
+            contents,
+            user: self.export_config.user.clone().unwrap(),
+            group: self.export_config.group.clone().unwrap(),
+            mode: self.export_config.permissions.unwrap(),
+        }))
     }
 
-    /// Get the parameter list required to render this template.
-    pub fn parameter_list(&self, handlebars: &Handlebars) -> Result<Vec<String>> {
+    /// Get the parameter list required to render this template, from its namespace and the
+    /// namespaces it imports.
+    pub fn parameter_list(
+        &self,
+        handlebars: &Handlebars,
+        imports: &[String],
+    ) -> Result<Vec<String>> {
+        if self.kind == Kind::Copy {
+            return Ok(Vec::new());
+        }
         let handlebars_template: &HandlebarsTemplate = match handlebars.get_template(&self.name) {
             Some(value) => value,
             None => return Err(anyhow!("could not find template {}", self.name)),
         };
 
+        let prefixes: Vec<String> = std::iter::once(self.namespace())
+            .chain(imports.iter().map(String::as_str))
+            .map(|namespace| format!("{}.", namespace))
+            .collect();
+        let known = |param: &str| prefixes.iter().any(|prefix| param.starts_with(prefix));
+
         let mut parameters: Vec<String> = Vec::new();
 
         for element in &handlebars_template.elements {
             if let HandlebarsTemplateElement::Expression(expression) = element {
-                if let HandlebarsParameter::Path(path) = &expression.name {
-                    if let HandlebarsPath::Relative(value) = path {
-                        let param: String = value.1.clone();
-                        if param.starts_with(&format!("{}.", self.namespace())) {
-                            parameters.push(value.1.clone());
-                        }
+                if let HandlebarsParameter::Path(HandlebarsPath::Relative(value)) = &expression.name
+                {
+                    let param: String = self.own_parameter(&value.1);
+                    if known(&param) {
+                        parameters.push(param);
                     }
                 }
             }
         }
+        for param in &self.front_matter.requires {
+            let param: String = self.own_parameter(param);
+            if known(&param) && !parameters.contains(&param) {
+                parameters.push(param);
+            }
+        }
         Ok(parameters)
     }
 
+    /// Get a parameter using the namespace name instead of `eri.ns`.
+    fn own_parameter(&self, param: &str) -> String {
+        match param.strip_prefix("eri.ns.") {
+            Some(value) => format!("{}.{}", self.namespace(), value),
+            None => param.to_owned(),
+        }
+    }
+
+    /// Check whether the condition under which this template is rendered holds.
+    pub fn condition(&self, handlebars: &Handlebars) -> Result<bool> {
+        let condition: &str = match &self.front_matter.when {
+            Some(value) => value,
+            None => return Ok(true),
+        };
+        let condition_template: String = format!("{{{{#if {}}}}}true{{{{/if}}}}", condition);
+        match handlebars.render_template(&condition_template, &self.context()) {
+            Ok(value) => Ok(value == "true"),
+            Err(e) => Err(anyhow!(
+                "failed to evaluate the condition of template {}: {}",
+                self.name,
+                e
+            )),
+        }
+    }
+
+    /// Get the output file name of this template, rendered using the template data.
+    pub fn output_filename(&self, handlebars: &Handlebars) -> Result<PathBuf> {
+        let filename: String = handlebars.render_template(self.filename(), &self.context())?;
+        let path: PathBuf = PathBuf::from(filename.trim());
+        if path.as_os_str().is_empty()
+            || !path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(anyhow!(
+                "template {} has an invalid output file name: {:?}",
+                self.name,
+                filename
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Get the required data keys that are missing from the data of this template.
+    pub fn missing_requirements(&self) -> Vec<&str> {
+        self.front_matter
+            .requires
+            .iter()
+            .filter(|key| self.lookup(key).is_none())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Get the data this template is rendered with.
+    fn context(&self) -> Context<'_> {
+        Context {
+            data: &self.data,
+            item: self.item.as_ref(),
+        }
+    }
+
+    /// Look up a dot separated key in the data this template is rendered with.
+    fn lookup(&self, key: &str) -> Option<&Value> {
+        let first: &str = key.split('.').next().unwrap_or_default();
+        if !self.context().item_keys().contains(&first) {
+            return data::lookup(&self.data, key);
+        }
+        match &self.item {
+            Some(Value::Object(item)) => data::lookup(item, key),
+            Some(item) if key == ITEM_KEY => Some(item),
+            _ => None,
+        }
+    }
+
     /// Get the name of the namespace of this template.
     pub fn namespace(&self) -> &str {
         let splits: &Vec<&str> = &self.name.split('/').collect();
@@ -137,46 +396,176 @@ impl<'a> Template<'a> {
 
     /// Get the file name of this template.
     pub fn filename(&self) -> &str {
-        let splits: &Vec<&str> = &self.name.split('/').collect();
-        splits[1]
-    }
-}
-
-fn chown(path: &PathBuf, user: &users::User, group: &users::Group) -> Result<()> {
-    let cstr_path: CString = CString::new(path.to_str().unwrap()).unwrap();
-    let ret_val: libc::c_int = unsafe { libc::chown(cstr_path.as_ptr(), user.uid(), group.gid()) };
-    if ret_val == -1 {
-        let errno_val: i32 = errno::errno().into();
-        match errno_val {
-            libc::EPERM => {
-                return Err(anyhow!(
-                    "chown: this process lacks permission to make the requested change"
-                ))
+        if let Some(filename) = &self.front_matter.filename {
+            return filename;
+        }
+        let splits: &Vec<&str> = &self.name.splitn(2, '/').collect();
+        let filename: &str = splits[1];
+        if self.kind == Kind::Template {
+            for ext in TEMPLATE_EXTENSIONS {
+                if let Some(value) = filename.strip_suffix(ext) {
+                    return value;
+                }
             }
-            libc::EROFS => return Err(anyhow!("chown: the file is on a read-only file system")),
-            _ => panic!("chown: unexpected errno: {}", errno_val),
         }
+        filename
     }
-    Ok(())
 }
 
-fn chmod(path: &PathBuf, mode: umask::Mode) -> Result<()> {
-    let cstr_path: CString = CString::new(path.to_str().unwrap()).unwrap();
-    let ret_val: libc::c_int = unsafe { libc::chmod(cstr_path.as_ptr(), mode.into()) };
-    if ret_val == -1 {
-        let errno_val: i32 = errno::errno().into();
-        match errno_val {
-            libc::ENOENT => return Err(anyhow!("chmod: the named file doesn’t exist")),
-            libc::EPERM => {
-                return Err(anyhow!(
-                    "chmod: this process does not have permission to change the access permissions of this file"
-                ))
-            }
-            libc::EROFS => {
-                return Err(anyhow!("chmod: the file resides on a read-only file system"))
-            }
-            _ => panic!("chmod: unexpected errno: {}", errno_val),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TestDir;
+
+    use serde_json::json;
+
+    /// Create a template from its source, in a directory of the test.
+    fn template(test: &str, name: &str, src: &str, data: Value) -> Template<'static> {
+        let dir: TestDir = TestDir::new(&format!("template-{}", test));
+        let path: PathBuf = dir.join(name.rsplit('/').next().unwrap());
+        std::fs::write(&path, src).unwrap();
+        let data: Map<String, Value> = match data {
+            Value::Object(value) => value,
+            _ => panic!("template data should be an object"),
+        };
+        let export_config: ExportConfig = ExportConfig {
+            dir: Some("/etc".to_owned()),
+            user: None,
+            group: None,
+            permissions: None,
+            root: None,
+        };
+        Template::new(
+            name.to_owned(),
+            path,
+            Cow::Owned(data),
+            Cow::Owned(export_config),
+            FrontMatter::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn filename_strips_template_extension() {
+        let template: Template = template("extension", "ns/app.conf.tmpl", "", json!({}));
+        assert_eq!(template.filename(), "app.conf");
+        assert_eq!(template.namespace(), "ns");
+    }
+
+    #[test]
+    fn output_filename_is_rendered() {
+        let template: Template = template(
+            "filename",
+            "ns/app.conf",
+            "---eri\nfilename: \"{{ns.name}}.conf\"\n---\n",
+            json!({"ns": {"name": "web"}}),
+        );
+        let handlebars: Handlebars = Handlebars::new();
+        assert_eq!(
+            template.output_filename(&handlebars).unwrap(),
+            PathBuf::from("web.conf")
+        );
+    }
+
+    #[test]
+    fn output_filename_stays_inside_export_dir() {
+        let template: Template = template(
+            "escape",
+            "ns/app.conf",
+            "---eri\nfilename: \"../{{ns.name}}\"\n---\n",
+            json!({"ns": {"name": "web"}}),
+        );
+        assert!(template.output_filename(&Handlebars::new()).is_err());
+    }
+
+    #[test]
+    fn condition_and_requirements() {
+        let template: Template = template(
+            "condition",
+            "ns/app.conf",
+            "---eri\nwhen: ns.tls\nrequires = [ns.cert, ns.key]\n---\n",
+            json!({"ns": {"tls": true, "cert": "a.pem"}}),
+        );
+        assert!(template.condition(&Handlebars::new()).unwrap());
+        assert_eq!(template.missing_requirements(), vec!["ns.key"]);
+    }
+
+    #[test]
+    fn parameter_list_of_imports() {
+        let template: Template = template(
+            "parameters",
+            "web/app.conf",
+            "---eri\nrequires = [eri.ns.name]\n---\n{{web.port}} {{db.host}} {{cache.host}}",
+            json!({}),
+        );
+        let mut handlebars: Handlebars = Handlebars::new();
+        template.register(&mut handlebars).unwrap();
+        assert_eq!(
+            template
+                .parameter_list(&handlebars, &["db".to_owned()])
+                .unwrap(),
+            vec!["web.port", "db.host", "web.name"]
+        );
+    }
+
+    #[test]
+    fn expand_merges_object_items() {
+        let template: Template = template(
+            "expand",
+            "ns/vhost.conf",
+            "---eri\nforeach: ns.vhosts\nfilename: \"sites/{{name}}.conf\"\nrequires = [port, ns.domain]\n---\n{{name}}.{{ns.domain}}:{{port}}",
+            json!({
+                "ns": {"domain": "example.com", "vhosts": [{"name": "a", "port": 80}, {"name": "b"}]},
+                "name": "shadowed",
+            }),
+        );
+        let templates: Vec<Template> = template.expand().unwrap();
+        assert_eq!(templates.len(), 2);
+        assert!(templates[0].missing_requirements().is_empty());
+        assert_eq!(templates[1].missing_requirements(), vec!["port"]);
+
+        let mut handlebars: Handlebars = Handlebars::new();
+        templates[0].register(&mut handlebars).unwrap();
+        let file: RenderedFile = templates[0].render(&mut handlebars).unwrap().unwrap();
+        assert_eq!(file.name, PathBuf::from("sites/a.conf"));
+        assert_eq!(file.contents, b"a.example.com:80");
+    }
+
+    #[test]
+    fn expand_exposes_scalar_items() {
+        let template: Template = template(
+            "expand-scalar",
+            "ns/user.conf",
+            "---eri\nforeach: ns.users\nfilename: \"{{item}}.conf\"\nrequires = [item]\n---\nuser {{item}}",
+            json!({"ns": {"users": ["alice", "bob"]}}),
+        );
+        let templates: Vec<Template> = template.expand().unwrap();
+        assert!(templates[1].missing_requirements().is_empty());
+
+        let mut handlebars: Handlebars = Handlebars::new();
+        templates[1].register(&mut handlebars).unwrap();
+        let file: RenderedFile = templates[1].render(&mut handlebars).unwrap().unwrap();
+        assert_eq!(file.name, PathBuf::from("bob.conf"));
+        assert_eq!(file.contents, b"user bob");
+    }
+
+    #[test]
+    fn expand_without_foreach() {
+        let template: Template = template("single", "ns/app.conf", "", json!({}));
+        let templates: Vec<Template> = template.expand().unwrap();
+        assert_eq!(templates.len(), 1);
+        assert!(templates[0].item.is_none());
+    }
+
+    #[test]
+    fn expand_rejects_non_list() {
+        let template: Template = template(
+            "non-list",
+            "ns/app.conf",
+            "---eri\nforeach: ns.list\n---\n",
+            json!({"ns": {"list": "a"}}),
+        );
+        assert!(template.expand().is_err());
     }
-    Ok(())
 }
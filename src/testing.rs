@@ -0,0 +1,62 @@
+use std::ops::Deref;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Once;
+
+use age::secrecy::ExposeSecret;
+use age::x25519::Identity;
+
+/// The number of test directories created so far, which keeps their names unique.
+static TEST_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once the age identity of the tests.
+static AGE_IDENTITY: Once = Once::new();
+
+/// Make a generated age identity the local identity, shared by all the tests since
+/// identities are loaded once.
+pub fn age_identity() {
+    AGE_IDENTITY.call_once(|| {
+        let identity: Identity = Identity::generate();
+        std::env::set_var("ERI_AGE_KEY", identity.to_string().expose_secret());
+    });
+}
+
+/// A temporary directory of a test, removed along with its contents when dropped.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Create an empty directory for a test, named after it and unique to the current run.
+    pub fn new(test: &str) -> Self {
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "eri-{}-{}-{}",
+            test,
+            std::process::id(),
+            TEST_DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::config::SecretSource;
+use crate::config::VaultConfig;
+use crate::sensitive;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use serde_json::json;
+use serde_json::Value;
+
+/// The timeout of the requests made to the API.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A client of a Vault compatible HTTP API.
+pub struct Client {
+    address: String,
+    token: String,
+}
+
+impl Client {
+    /// Create a new client.
+    pub fn new(config: &VaultConfig) -> Result<Self> {
+        let address: String = match config
+            .address
+            .clone()
+            .or_else(|| std::env::var("VAULT_ADDR").ok())
+        {
+            Some(value) => value.trim_end_matches('/').to_owned(),
+            None => {
+                return Err(anyhow!(
+                    "the address of the vault API is not set(vault.address or VAULT_ADDR)"
+                ))
+            }
+        };
+
+        let token: String = get_token(config, &address)?;
+        sensitive::mark_str(&token);
+        Ok(Client::with_token(address, token))
+    }
+
+    /// Create a new client authenticating with a token.
+    pub fn with_token(address: String, token: String) -> Self {
+        Client { address, token }
+    }
+
+    /// Read a secret from a KV secrets engine.
+    pub fn read(&self, source: &SecretSource) -> Result<Value> {
+        let mount: &str = source
+            .mount
+            .as_deref()
+            .unwrap_or("secret")
+            .trim_matches('/');
+        let path: &str = source.path.trim_matches('/');
+        let url: String = match source.kv_version.unwrap_or(2) {
+            1 => format!("{}/v1/{}/{}", self.address, mount, path),
+            2 => format!("{}/v1/{}/data/{}", self.address, mount, path),
+            version => {
+                return Err(anyhow!(
+                    "unsupported KV secrets engine version: {}",
+                    version
+                ))
+            }
+        };
+
+        log::debug!("Reading secret {}/{}", mount, path);
+        let response: ureq::Response = ureq::get(&url)
+            .set("X-Vault-Token", &self.token)
+            .timeout(TIMEOUT)
+            .call();
+        let body: Value = check_response(response, &url)?;
+
+        let mut secret: Value = match source.kv_version.unwrap_or(2) {
+            1 => body["data"].clone(),
+            _ => body["data"]["data"].clone(),
+        };
+        if !secret.is_object() {
+            return Err(anyhow!("no secret found at {}/{}", mount, path));
+        }
+        if let Some(field) = &source.field {
+            secret = match secret.get(field) {
+                Some(value) => value.clone(),
+                None => return Err(anyhow!("secret {}/{} has no field {}", mount, path, field)),
+            };
+        }
+
+        sensitive::mark(&secret);
+        Ok(secret)
+    }
+}
+
+/// Get a token, logging in using AppRole if a role id is available.
+fn get_token(config: &VaultConfig, address: &str) -> Result<String> {
+    if let Some(token_file) = &config.token_file {
+        return read_trimmed(token_file);
+    }
+    if let Ok(token) = std::env::var("VAULT_TOKEN") {
+        return Ok(token);
+    }
+
+    let role_id: Option<String> = match (&config.role_id, &config.role_id_file) {
+        (Some(role_id), _) => Some(role_id.clone()),
+        (None, Some(role_id_file)) => Some(read_trimmed(role_id_file)?),
+        (None, None) => std::env::var("VAULT_ROLE_ID").ok(),
+    };
+    if let Some(role_id) = role_id {
+        let secret_id: String = match &config.secret_id_file {
+            Some(secret_id_file) => read_trimmed(secret_id_file)?,
+            None => match std::env::var("VAULT_SECRET_ID") {
+                Ok(value) => value,
+                Err(_) => {
+                    return Err(anyhow!(
+                        "no secret id for AppRole(vault.secret_id_file or VAULT_SECRET_ID)"
+                    ))
+                }
+            },
+        };
+        sensitive::mark_str(&secret_id);
+        return approle_login(config, address, &role_id, &secret_id);
+    }
+
+    if let Some(home) = std::env::var_os("HOME") {
+        let token_file: PathBuf = PathBuf::from(home).join(".vault-token");
+        if token_file.is_file() {
+            return read_trimmed(token_file.to_str().unwrap());
+        }
+    }
+
+    Err(anyhow!(
+        "no vault token found(vault.token_file, VAULT_TOKEN, AppRole or ~/.vault-token)"
+    ))
+}
+
+/// Log in using AppRole.
+fn approle_login(
+    config: &VaultConfig,
+    address: &str,
+    role_id: &str,
+    secret_id: &str,
+) -> Result<String> {
+    let mount: &str = config
+        .approle_mount
+        .as_deref()
+        .unwrap_or("approle")
+        .trim_matches('/');
+    let url: String = format!("{}/v1/auth/{}/login", address, mount);
+
+    log::debug!("Logging in to vault using AppRole");
+    let response: ureq::Response = ureq::post(&url)
+        .timeout(TIMEOUT)
+        .send_json(json!({ "role_id": role_id, "secret_id": secret_id }));
+    let body: Value = check_response(response, &url)?;
+
+    match body["auth"]["client_token"].as_str() {
+        Some(value) => Ok(value.to_owned()),
+        None => Err(anyhow!("AppRole login response contains no token")),
+    }
+}
+
+/// Check that a response is successful and get its body.
+fn check_response(response: ureq::Response, url: &str) -> Result<Value> {
+    if let Some(e) = response.synthetic_error() {
+        return Err(anyhow!("request to {} failed: {}", url, e));
+    }
+    if !response.ok() {
+        let status: u16 = response.status();
+        let errors: String = match response.into_json() {
+            Ok(body) => body["errors"].to_string(),
+            Err(_) => String::new(),
+        };
+        return Err(anyhow!(
+            "request to {} failed with status {}: {}",
+            url,
+            status,
+            errors
+        ));
+    }
+    Ok(response.into_json()?)
+}
+
+/// Read a file, without the surrounding whitespace.
+fn read_trimmed(path: &str) -> Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(value) => Ok(value.trim().to_owned()),
+        Err(e) => Err(anyhow!("failed to read {}: {}", path, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// Serve a single request with a response, returning the request that was received.
+    fn serve(status: &str, body: &'static str) -> (String, JoinHandle<String>) {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address: String = format!("http://{}", listener.local_addr().unwrap());
+        let status: String = status.to_owned();
+        let handle: JoinHandle<String> = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request: Vec<u8> = Vec::new();
+            let mut buf: [u8; 1024] = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let len: usize = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (address, handle)
+    }
+
+    /// Create a secret source.
+    fn source(path: &str, kv_version: Option<i64>, field: Option<&str>) -> SecretSource {
+        SecretSource {
+            path: path.to_owned(),
+            mount: None,
+            kv_version,
+            field: field.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn read_kv2_field() {
+        let (address, handle) = serve(
+            "200 OK",
+            r#"{"data": {"data": {"password": "hunter22"}, "metadata": {}}}"#,
+        );
+        let client: Client = Client::with_token(address, "s.token".to_owned());
+        let value: Value = client.read(&source("db/", None, Some("password"))).unwrap();
+        assert_eq!(value, json!("hunter22"));
+
+        let request: String = handle.join().unwrap().to_lowercase();
+        assert!(request.starts_with("get /v1/secret/data/db "));
+        assert!(request.contains("x-vault-token: s.token"));
+    }
+
+    #[test]
+    fn read_kv1() {
+        let (address, handle) = serve("200 OK", r#"{"data": {"user": "eri"}}"#);
+        let client: Client = Client::with_token(address, "s.token".to_owned());
+        let value: Value = client.read(&source("db", Some(1), None)).unwrap();
+        assert_eq!(value, json!({"user": "eri"}));
+        assert!(handle.join().unwrap().starts_with("GET /v1/secret/db "));
+    }
+
+    #[test]
+    fn read_denied() {
+        let (address, handle) = serve("403 Forbidden", r#"{"errors": ["permission denied"]}"#);
+        let client: Client = Client::with_token(address, "s.token".to_owned());
+        let e: String = client
+            .read(&source("db", None, None))
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("403"), "{}", e);
+        assert!(e.contains("permission denied"), "{}", e);
+        handle.join().unwrap();
+    }
+}